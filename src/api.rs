@@ -0,0 +1,285 @@
+use iron::prelude::*;
+use iron::status;
+use iron::method::Method;
+use iron::mime::{Mime, TopLevel, SubLevel};
+use iron::headers::{Allow, ContentType};
+use regex::Regex;
+
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::{self, Value};
+use serde_json::value::ToJson;
+
+use audit::{self, Source};
+use events::{EventBus, StateEvent};
+use identity::{self, IdentityFields};
+use logstore::LogStore;
+use trace;
+use super::{cfti_send, send_and_await, shut_down, InterfaceState, OutgoingMessage, MAX_WAIT_MS};
+
+/// Largest request body that will be read.
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+/// Every field a request body may have.  Each endpoint uses the ones it needs.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ApiRequest {
+    #[serde(default)]
+    scenario: Option<String>,
+
+    #[serde(default)]
+    reason: Option<String>,
+
+    #[serde(default)]
+    serial: Option<String>,
+
+    #[serde(default)]
+    lot: Option<String>,
+
+    #[serde(default)]
+    work_order: Option<String>,
+
+    #[serde(default)]
+    operator: Option<String>,
+
+    /// How long to wait for exclave to answer, in milliseconds.
+    #[serde(default)]
+    wait_ms: Option<u64>,
+
+    /// Whether protocol tracing should be on.
+    #[serde(default)]
+    enabled: Option<bool>,
+}
+
+impl ApiRequest {
+    /// The fields that were given, for the audit log.
+    fn parameters(&self) -> Vec<(&'static str, String)> {
+        let fields = [("scenario", &self.scenario), ("reason", &self.reason), ("serial", &self.serial),
+                      ("lot", &self.lot), ("work_order", &self.work_order), ("operator", &self.operator)];
+        let mut parameters: Vec<(&'static str, String)> = fields.iter()
+                                                                .filter_map(|&(name, value)| value.clone().map(|v| (name, v)))
+                                                                .collect();
+        if let Some(ms) = self.wait_ms {
+            parameters.push(("wait_ms", ms.to_string()));
+        }
+        if let Some(enabled) = self.enabled {
+            parameters.push(("enabled", enabled.to_string()));
+        }
+        parameters
+    }
+
+    fn identity(&self) -> IdentityFields {
+        IdentityFields {
+            serial: self.serial.clone(),
+            lot: self.lot.clone(),
+            work_order: self.work_order.clone(),
+            operator: self.operator.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Success {
+    ok: bool,
+    data: Value,
+}
+
+#[derive(Serialize)]
+struct Failure {
+    ok: bool,
+    error: ApiError,
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct Started {
+    scenario: String,
+
+    /// Exclave's START, if the request waited for it.
+    event: Option<StateEvent>,
+}
+
+#[derive(Serialize)]
+struct Scenario {
+    scenario: String,
+}
+
+#[derive(Serialize)]
+struct Tracing {
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct Empty {}
+
+/// What an endpoint did, or the status and message to fail with.
+type Outcome = Result<Value, (status::Status, String)>;
+
+fn done<T: ToJson>(data: T) -> Outcome {
+    Ok(data.to_json().unwrap())
+}
+
+fn success(data: Value) -> Response {
+    let content_type = "application/json".parse::<Mime>().unwrap();
+    let body = Success { ok: true, data };
+    Response::with((content_type, status::Ok, serde_json::to_string(&body).unwrap()))
+}
+
+pub fn failure<M: Into<String>>(status: status::Status, message: M) -> Response {
+    let content_type = "application/json".parse::<Mime>().unwrap();
+    let body = Failure {
+        ok: false,
+        error: ApiError {
+            status: status.to_u16(),
+            message: message.into(),
+        },
+    };
+    Response::with((content_type, status, serde_json::to_string(&body).unwrap()))
+}
+
+/// Read the request's JSON body.  A missing body is the same as an empty object.
+fn read_body(request: &mut Request) -> Result<ApiRequest, (status::Status, String)> {
+    // Requiring JSON, even with no body, means a page on another site can't
+    // make the browser send one of these without the browser asking us first.
+    if !matches!(request.headers.get::<ContentType>(), Some(&ContentType(Mime(TopLevel::Application, SubLevel::Json, _)))) {
+        return Err((status::UnsupportedMediaType, "Requests must be sent as application/json, even without a body".to_string()));
+    }
+
+    let mut body = String::new();
+    if let Err(e) = request.body.by_ref().take(MAX_BODY_BYTES).read_to_string(&mut body) {
+        return Err((status::BadRequest, format!("Unable to read request body: {}", e)));
+    }
+    if body.trim().is_empty() {
+        return Ok(ApiRequest::default());
+    }
+
+    serde_json::from_str(&body).map_err(|e| (status::BadRequest, format!("Invalid request body: {}", e)))
+}
+
+fn start(body: ApiRequest,
+         state: &Arc<Mutex<InterfaceState>>,
+         events: &Arc<Mutex<EventBus>>,
+         pattern: &Option<Regex>) -> Outcome {
+    // The serial number, if given, belongs to the unit about to be tested.
+    if body.serial.is_some() {
+        identity::update_identity(body.identity(), state, events, pattern).map_err(|e| (status::BadRequest, e))?;
+    }
+
+    let scenario = match body.scenario {
+        Some(s) => s,
+        None => state.lock().unwrap().scenario.clone(),
+    };
+    let message = OutgoingMessage::StartTests(scenario.clone());
+    let wait = match body.wait_ms {
+        None => {
+            cfti_send(message);
+            return done(Started { scenario, event: None });
+        },
+        Some(ms) => Duration::from_millis(ms.min(MAX_WAIT_MS)),
+    };
+
+    match send_and_await(message, wait, events, |e| matches!(e, StateEvent::Start { .. })) {
+        Some(event) => done(Started { scenario, event: Some(event) }),
+        None => Err((status::GatewayTimeout, format!("Timed out after {} ms waiting for START from exclave", wait.as_millis()))),
+    }
+}
+
+/// Serve everything under /api/v1.  Only POST, PUT, and DELETE are accepted,
+/// since every request here changes something, and each one for a real
+/// endpoint is recorded in the audit log, along with why it was turned
+/// away if its body couldn't be read.
+pub fn handle(request: &mut Request,
+              state: &Arc<Mutex<InterfaceState>>,
+              logs: &Arc<Mutex<LogStore>>,
+              events: &Arc<Mutex<EventBus>>,
+              pattern: &Option<Regex>) -> IronResult<Response> {
+    let path = request.url.path().join("/");
+    let allowed = match path.as_str() {
+        "start" | "abort" | "exit" => vec![Method::Post],
+        "scenario" => vec![Method::Put],
+        "identity" => vec![Method::Put, Method::Delete],
+        "logs" => vec![Method::Delete],
+        "trace" => vec![Method::Put, Method::Delete],
+        _ => return Ok(failure(status::NotFound, format!("No such API endpoint: /api/v1/{}", path))),
+    };
+    if !allowed.contains(&request.method) {
+        let mut response = failure(status::MethodNotAllowed, format!("/api/v1/{} does not accept {}", path, request.method));
+        response.headers.set(Allow(allowed));
+        return Ok(response);
+    }
+
+    let action = audit::action_for(&request.method, &["api", "v1", path.as_str()]).unwrap();
+    let body = match read_body(request) {
+        Ok(b) => b,
+        Err((status, message)) => {
+            audit::record(&Source::of(request), action, &[], Some(message.clone()));
+            return Ok(failure(status, message));
+        },
+    };
+
+    let parameters = body.parameters();
+    let reason = body.reason.clone();
+    let outcome = match (request.method.clone(), path.as_str()) {
+        (Method::Post, "start") => start(body, state, events, pattern),
+        (Method::Post, "abort") => {
+            cfti_send(OutgoingMessage::AbortTests);
+            done(Empty {})
+        },
+        // Shut down once the audit entry has gone out, below.
+        (Method::Post, "exit") => done(Empty {}),
+        (Method::Put, "scenario") => match body.scenario {
+            None => Err((status::BadRequest, "A scenario is required".to_string())),
+            Some(scenario) => {
+                cfti_send(OutgoingMessage::Scenario(scenario.clone()));
+                done(Scenario { scenario })
+            },
+        },
+        (Method::Put, "identity") => identity::update_identity(body.identity(), state, events, pattern)
+                                              .map_err(|e| (status::BadRequest, e))
+                                              .and_then(done),
+        (Method::Delete, "identity") => {
+            identity::forget_identity(state, events);
+            done(Empty {})
+        },
+        (Method::Delete, "logs") => {
+            logs.lock().unwrap().truncate();
+            done(Empty {})
+        },
+        (Method::Put, "trace") => match body.enabled {
+            None => Err((status::BadRequest, "\"enabled\" is required".to_string())),
+            Some(enabled) => {
+                trace::set_enabled(enabled);
+                done(Tracing { enabled })
+            },
+        },
+        (Method::Delete, "trace") => {
+            trace::clear();
+            done(Empty {})
+        },
+        _ => unreachable!(),
+    };
+
+    audit::record(&Source::of(request), action, &parameters, outcome.as_ref().err().map(|e| e.1.clone()));
+    if action == "exit" {
+        shut_down(reason.unwrap_or_else(|| "Requested through the API".to_string()));
+    }
+
+    Ok(match outcome {
+        Ok(data) => success(data),
+        Err((status, message)) => failure(status, message),
+    })
+}
+
+/// Stands in for a legacy GET route when --legacy-get isn't given.  The
+/// route is gone whatever the method, so this is 410 rather than 405.
+pub fn legacy_disabled(_: &mut Request, path: &str, replacement: &str) -> IronResult<Response> {
+    Ok(failure(status::Gone,
+               format!("GET {} no longer changes anything.  Use {} instead, or start the server with --legacy-get.", path, replacement)))
+}
@@ -0,0 +1,252 @@
+use iron::prelude::*;
+use iron::status;
+use iron::mime::Mime;
+use iron::headers::{Headers, UserAgent};
+use iron::method::Method;
+use urlencoded;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde_json;
+
+use auth::Caller;
+use history::parse_number;
+use super::{cfti_send, unix_time, OutgoingMessage};
+
+/// Most entries kept in memory for /audit.json.  The file keeps them all.
+const MEMORY_ENTRIES: usize = 100_000;
+
+/// Entries returned by /audit.json unless "limit" says otherwise.
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// Most entries returned by one request.
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Written to by the HTTP handlers and the WebSocket server alike.
+static AUDIT: Mutex<AuditLog> = Mutex::new(AuditLog::new());
+
+/// One control action, and how it turned out.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct AuditEntry {
+    /// Increases by one for every entry, and carries on across restarts
+    /// if --audit-log is given.
+    seq: u64,
+    timestamp: Duration,
+
+    /// The client's IP address.
+    client: String,
+
+    /// Who the client logged in as, if --auth is given.
+    user: Option<String>,
+
+    /// The client's User-Agent, which tells browsers apart.
+    user_agent: Option<String>,
+
+    /// Such as "start" or "abort".
+    action: String,
+    parameters: BTreeMap<String, String>,
+    ok: bool,
+
+    /// Why the action failed, if it did.
+    error: Option<String>,
+}
+
+impl AuditEntry {
+    /// The entry as a line of text, for sending to exclave.
+    fn describe(&self) -> String {
+        let mut text = format!("Audit: {} by {} from {}", self.action, self.user.as_deref().unwrap_or("unknown user"), self.client);
+        for (name, value) in &self.parameters {
+            text.push_str(&format!(" {}={:?}", name, value));
+        }
+        match self.error {
+            None => text.push_str(": ok"),
+            Some(ref e) => text.push_str(&format!(": failed: {}", e)),
+        }
+        text
+    }
+}
+
+struct AuditLog {
+    entries: VecDeque<AuditEntry>,
+    next_seq: u64,
+
+    /// Where every entry is appended, if anywhere.
+    file: Option<File>,
+}
+
+impl AuditLog {
+    const fn new() -> AuditLog {
+        AuditLog {
+            entries: VecDeque::new(),
+            next_seq: 1,
+            file: None,
+        }
+    }
+
+    fn remember(&mut self, entry: AuditEntry) {
+        self.next_seq = self.next_seq.max(entry.seq + 1);
+        self.entries.push_back(entry);
+        while self.entries.len() > MEMORY_ENTRIES {
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// Append every entry to `path`, one JSON object per line, after reading
+/// back the entries already there.  Lines that can't be read, such as one
+/// left half-written by a crash, are skipped.
+pub fn open(path: &str) -> Result<(), String> {
+    let mut audit = AUDIT.lock().unwrap();
+    let file = OpenOptions::new().read(true).append(true).create(true).open(path).map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(file);
+    let mut number = 0;
+    loop {
+        let mut line = vec![];
+        if reader.read_until(b'\n', &mut line).map_err(|e| e.to_string())? == 0 {
+            break;
+        }
+        number += 1;
+
+        // An entry that was only partly written when we last stopped.  Finish
+        // the line off so that the next entry starts on a line of its own.
+        if !line.ends_with(b"\n") {
+            reader.get_mut().write_all(b"\n").map_err(|e| e.to_string())?;
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => audit.remember(entry),
+            Err(e) => eprintln!("Skipping unreadable entry on line {} of {}: {}", number, path, e),
+        }
+    }
+    audit.file = Some(reader.into_inner());
+    Ok(())
+}
+
+/// Where a control action came from.
+pub struct Source {
+    client: String,
+    user: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Source {
+    /// `caller` is None when --auth isn't given.
+    pub fn new(address: Option<SocketAddr>, headers: &Headers, caller: Option<&Caller>) -> Source {
+        Source {
+            client: address.map(|a| a.ip().to_string()).unwrap_or_else(|| "unknown".to_string()),
+            user: caller.map(|c| c.name.clone()),
+            user_agent: headers.get::<UserAgent>().map(|u| u.to_string()),
+        }
+    }
+
+    pub fn of(request: &Request) -> Source {
+        Source::new(Some(request.remote_addr), &request.headers, request.extensions.get::<Caller>())
+    }
+}
+
+/// Record a control action, and pass it on to exclave as a LOG line.
+/// `error` says why it failed, if it did.
+pub fn record(source: &Source, action: &str, parameters: &[(&str, String)], error: Option<String>) {
+    let entry = {
+        let mut audit = AUDIT.lock().unwrap();
+        let entry = AuditEntry {
+            seq: audit.next_seq,
+            timestamp: unix_time(),
+            client: source.client.clone(),
+            user: source.user.clone(),
+            user_agent: source.user_agent.clone(),
+            action: action.to_string(),
+            parameters: parameters.iter().map(|&(n, ref v)| (n.to_string(), v.clone())).collect(),
+            ok: error.is_none(),
+            error,
+        };
+        if let Some(ref mut file) = audit.file {
+            let written = writeln!(file, "{}", serde_json::to_string(&entry).unwrap()).and_then(|_| file.sync_data());
+            if let Err(e) = written {
+                eprintln!("Unable to write to the audit log: {}", e);
+            }
+        }
+        audit.remember(entry.clone());
+        entry
+    };
+    cfti_send(OutgoingMessage::Log(entry.describe()));
+}
+
+/// The control action that a request would carry out, so that one turned
+/// away before reaching its handler can still be recorded.  None if the
+/// request doesn't change anything.
+pub fn action_for(method: &Method, path: &[&str]) -> Option<&'static str> {
+    Some(match (method, path) {
+        (&Method::Post, ["api", "v1", "start"]) | (_, ["start", ..]) => "start",
+        (&Method::Post, ["api", "v1", "abort"]) | (_, ["abort", ..]) => "abort",
+        (&Method::Post, ["api", "v1", "exit"]) | (_, ["exit", ..]) => "exit",
+        (&Method::Put, ["api", "v1", "scenario"]) | (_, ["scenario", ..]) => "scenario",
+        (&Method::Delete, ["api", "v1", "identity"]) | (_, ["identity", "clear", ..]) => "clear_identity",
+        (&Method::Put, ["api", "v1", "identity"]) | (_, ["identity", ..]) => "identity",
+        (&Method::Delete, ["api", "v1", "logs"]) | (_, ["truncate", ..]) => "truncate",
+        (&Method::Put, ["api", "v1", "trace"]) | (_, ["protocol", "trace", "enable", ..]) |
+        (_, ["protocol", "trace", "disable", ..]) => "trace",
+        (&Method::Delete, ["api", "v1", "trace"]) | (_, ["protocol", "trace", "clear", ..]) => "clear_trace",
+        (_, ["hello", ..]) => "hello",
+        _ => return None,
+    })
+}
+
+/// Record a legacy GET request as a control action, judging whether it
+/// worked by the response's status.
+pub fn record_response(request: &Request, action: &str, parameters: &[(&str, String)], response: &IronResult<Response>) {
+    let error = match *response {
+        Ok(ref r) => match r.status {
+            Some(s) if !s.is_success() => Some(s.to_string()),
+            _ => None,
+        },
+        Err(ref e) => Some(e.to_string()),
+    };
+    record(&Source::of(request), action, parameters, error);
+}
+
+/// Serve /audit.json, filtered by "user", "action", and "since" and "until"
+/// (Unix times in seconds).  Returns the most recent "limit" entries that
+/// match, or with "after", the first "limit" entries following that seq.
+pub fn show_audit(request: &mut Request) -> IronResult<Response> {
+    let query = match request.get_ref::<urlencoded::UrlEncodedQuery>() {
+        Ok(hashmap) => hashmap.clone(),
+        Err(_) => HashMap::new(),
+    };
+    let after: Option<u64> = match parse_number(&query, "after") { Ok(v) => v, Err(r) => return Ok(r) };
+    let limit = match parse_number(&query, "limit") { Ok(v) => v.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE), Err(r) => return Ok(r) };
+    let since: Option<f64> = match parse_number(&query, "since") { Ok(v) => v, Err(r) => return Ok(r) };
+    let until: Option<f64> = match parse_number(&query, "until") { Ok(v) => v, Err(r) => return Ok(r) };
+    let user = query.get("user").map(|u| &u[0]);
+    let action = query.get("action").map(|a| &a[0]);
+
+    let audit = AUDIT.lock().unwrap();
+    let matching = audit.entries.iter().filter(|e| {
+        let time = e.timestamp.as_secs_f64();
+        after.is_none_or(|after| e.seq > after)
+            && user.is_none_or(|u| e.user.as_ref() == Some(u))
+            && action.is_none_or(|a| &e.action == a)
+            && since.is_none_or(|since| time >= since)
+            && until.is_none_or(|until| time <= until)
+    });
+    let mut page: Vec<&AuditEntry> = if after.is_some() {
+        matching.take(limit).collect()
+    } else {
+        matching.rev().take(limit).collect()
+    };
+    if after.is_none() {
+        page.reverse();
+    }
+
+    let content_type = "application/json".parse::<Mime>().unwrap();
+    Ok(Response::with((content_type, status::Ok, serde_json::to_string(&page).unwrap())))
+}
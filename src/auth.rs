@@ -0,0 +1,485 @@
+use iron::prelude::*;
+use iron::status;
+use iron::{BeforeMiddleware, typemap};
+use iron::headers::{Authorization, Basic, Bearer, Headers};
+use pbkdf2::pbkdf2_hmac;
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+use serde_json;
+
+use api;
+use audit::{self, Source};
+
+/// PBKDF2 rounds used by --hash-password.
+const PASSWORD_ROUNDS: u32 = 100_000;
+
+/// Prefix of a password hash, which is followed by "$ROUNDS$SALT$HASH".
+const PASSWORD_SCHEME: &str = "pbkdf2-sha256";
+
+/// Realm that browsers show when asking for a username and password.
+const REALM: &str = "jig-20";
+
+/// What a caller is allowed to do.  Each role may do everything the ones
+/// before it may.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Read the state, logs, reports, and run history.
+    Viewer,
+
+    /// Start and abort tests, select scenarios, and identify units.
+    Operator,
+
+    /// Truncate logs, and see and control the raw protocol.
+    Engineer,
+
+    /// Shut down exclave and the interface.
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Engineer => "engineer",
+            Role::Admin => "admin",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The role needed for a request to `path`.  Every route mounted in main
+/// must be covered here, since anything not listed only needs Viewer, and
+/// listed in the test of this below.
+pub fn required_role(path: &[&str]) -> Role {
+    match path {
+        ["exit", ..] | ["api", "v1", "exit", ..] => Role::Admin,
+        ["truncate", ..] | ["stdin.txt", ..] | ["audit.json", ..] | ["protocol", "trace", ..] |
+        ["api", "v1", "logs", ..] | ["api", "v1", "trace", ..] => Role::Engineer,
+        ["start", ..] | ["abort", ..] | ["scenario", ..] | ["identity", ..] | ["hello", ..] |
+        ["jig", ..] | ["scenarios", ..] | ["tests", ..] | ["api", "v1", ..] => Role::Operator,
+        _ => Role::Viewer,
+    }
+}
+
+/// The --auth file, as written.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuthFile {
+    /// Role given to requests without any credentials.  If missing, they are refused.
+    #[serde(default)]
+    anonymous: Option<Role>,
+
+    #[serde(default)]
+    users: Vec<UserEntry>,
+
+    #[serde(default)]
+    tokens: Vec<TokenEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserEntry {
+    name: String,
+
+    /// As printed by --hash-password.
+    password: String,
+    role: Role,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenEntry {
+    name: String,
+
+    /// SHA-256 of the token, in hex.
+    sha256: String,
+    role: Role,
+}
+
+/// A password hash from the --auth file.
+struct PasswordHash {
+    rounds: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    fn parse(text: &str) -> Result<PasswordHash, String> {
+        let parts: Vec<&str> = text.split('$').collect();
+        match parts[..] {
+            [PASSWORD_SCHEME, rounds, salt, hash] => Ok(PasswordHash {
+                rounds: rounds.parse().map_err(|_| format!("Invalid number of rounds {:?}", rounds))?,
+                salt: from_hex(salt)?,
+                hash: from_hex(hash)?,
+            }),
+            _ => Err(format!("Password hashes must look like {}$ROUNDS$SALT$HASH", PASSWORD_SCHEME)),
+        }
+    }
+
+    fn derive(password: &str, salt: &[u8], rounds: u32) -> Vec<u8> {
+        let mut hash = vec![0; 32];
+        pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut hash);
+        hash
+    }
+
+    fn matches(&self, password: &str) -> bool {
+        same_bytes(&PasswordHash::derive(password, &self.salt, self.rounds), &self.hash)
+    }
+}
+
+struct User {
+    hash: PasswordHash,
+    role: Role,
+}
+
+struct Token {
+    name: String,
+    sha256: Vec<u8>,
+    role: Role,
+}
+
+/// Who made a request, once they have been let in.  Stored in the
+/// request's extensions.
+#[derive(Clone, Debug)]
+pub struct Caller {
+    /// The username or token name, or "anonymous".
+    pub name: String,
+    pub role: Role,
+}
+
+impl typemap::Key for Caller {
+    type Value = Caller;
+}
+
+/// Why a request was turned away.
+#[derive(Debug)]
+pub enum Refusal {
+    /// There were no usable credentials, so the client should log in.
+    Unauthorized(String),
+
+    /// The caller's role may not do this.
+    Forbidden(String),
+}
+
+impl Refusal {
+    pub fn status(&self) -> status::Status {
+        match *self {
+            Refusal::Unauthorized(_) => status::Unauthorized,
+            Refusal::Forbidden(_) => status::Forbidden,
+        }
+    }
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Refusal::Unauthorized(ref m) | Refusal::Forbidden(ref m) => write!(f, "{}", m),
+        }
+    }
+}
+
+impl Error for Refusal {}
+
+/// Users and tokens loaded from the --auth file.
+pub struct Auth {
+    anonymous: Option<Role>,
+    users: HashMap<String, User>,
+    tokens: Vec<Token>,
+
+    /// SHA-256 of each user's password once it has been checked, so that
+    /// PBKDF2 doesn't run on every request.
+    verified: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl Auth {
+    pub fn load(path: &str) -> Result<Auth, String> {
+        let mut text = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| e.to_string())?;
+        let file: AuthFile = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+        let mut users = HashMap::new();
+        for user in file.users {
+            let hash = PasswordHash::parse(&user.password).map_err(|e| format!("User {}: {}", user.name, e))?;
+            if users.insert(user.name.clone(), User { hash, role: user.role }).is_some() {
+                return Err(format!("User {} is listed more than once", user.name));
+            }
+        }
+        let mut tokens = vec![];
+        for token in file.tokens {
+            let sha256 = from_hex(&token.sha256).map_err(|e| format!("Token {}: {}", token.name, e))?;
+            if sha256.len() != 32 {
+                return Err(format!("Token {}: sha256 must be 64 hex digits", token.name));
+            }
+            tokens.push(Token { name: token.name, sha256, role: token.role });
+        }
+        if users.is_empty() && tokens.is_empty() && file.anonymous.is_none() {
+            return Err("No users, tokens, or anonymous role are defined, so nobody could get in".to_string());
+        }
+
+        Ok(Auth {
+            anonymous: file.anonymous,
+            users,
+            tokens,
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn check_password(&self, name: &str, password: &str) -> Option<Role> {
+        let user = self.users.get(name)?;
+        let digest = Sha256::digest(password.as_bytes()).to_vec();
+        if self.verified.lock().unwrap().get(name).is_some_and(|d| same_bytes(d, &digest)) {
+            return Some(user.role);
+        }
+        if !user.hash.matches(password) {
+            return None;
+        }
+        self.verified.lock().unwrap().insert(name.to_string(), digest);
+        Some(user.role)
+    }
+
+    fn check_token(&self, token: &str) -> Option<&Token> {
+        let digest = Sha256::digest(token.as_bytes());
+        self.tokens.iter().find(|t| same_bytes(&t.sha256, &digest))
+    }
+
+    /// Work out who sent `headers`, or `query_token` if the client can't set
+    /// headers, and make sure they are at least `needed`.
+    pub fn check(&self, headers: &Headers, query_token: Option<&str>, needed: Role) -> Result<Caller, Refusal> {
+        let caller = if let Some(basic) = headers.get::<Authorization<Basic>>() {
+            let password = basic.password.as_deref().unwrap_or("");
+            match self.check_password(&basic.username, password) {
+                Some(role) => Caller { name: basic.username.clone(), role },
+                None => return Err(Refusal::Unauthorized("Incorrect username or password".to_string())),
+            }
+        } else if let Some(token) = headers.get::<Authorization<Bearer>>().map(|b| b.token.as_str()).or(query_token) {
+            match self.check_token(token) {
+                Some(t) => Caller { name: t.name.clone(), role: t.role },
+                None => return Err(Refusal::Unauthorized("Unknown token".to_string())),
+            }
+        } else {
+            match self.anonymous {
+                Some(role) => Caller { name: "anonymous".to_string(), role },
+                None => return Err(Refusal::Unauthorized("Log in with a username and password, or a token".to_string())),
+            }
+        };
+
+        if caller.role < needed {
+            return Err(Refusal::Forbidden(format!("{} has the {} role, but this needs {} or above", caller.name, caller.role, needed)));
+        }
+        Ok(caller)
+    }
+}
+
+/// Checks every request against the --auth file before it reaches its handler.
+pub struct Enforce(pub Arc<Auth>);
+
+impl BeforeMiddleware for Enforce {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let path = request.url.path();
+        let refusal = match self.0.check(&request.headers, None, required_role(&path)) {
+            Ok(caller) => {
+                request.extensions.insert::<Caller>(caller);
+                return Ok(());
+            },
+            Err(r) => r,
+        };
+        if let Some(action) = audit::action_for(&request.method, &path) {
+            audit::record(&Source::of(request), action, &[], Some(refusal.to_string()));
+        }
+
+        let mut response = if path.starts_with(&["api", "v1"]) {
+            api::failure(refusal.status(), refusal.to_string())
+        } else {
+            Response::with((refusal.status(), refusal.to_string()))
+        };
+        if let Refusal::Unauthorized(_) = refusal {
+            response.headers.set_raw("WWW-Authenticate", vec![format!("Basic realm=\"{}\"", REALM).into_bytes()]);
+        }
+        Err(IronError { error: Box::new(refusal), response })
+    }
+}
+
+/// Hash `password` for the "password" field of a user in the --auth file.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let mut salt = [0; 16];
+    File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut salt))
+                              .map_err(|e| format!("Unable to generate a salt: {}", e))?;
+    let hash = PasswordHash::derive(password, &salt, PASSWORD_ROUNDS);
+    Ok(format!("{}${}${}${}", PASSWORD_SCHEME, PASSWORD_ROUNDS, to_hex(&salt), to_hex(&hash)))
+}
+
+/// Compare two secrets in a time that doesn't depend on where they differ.
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>, String> {
+    // from_str_radix would also take a sign, such as "+1".
+    if text.len() % 2 != 0 || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("Invalid hex {:?}", text));
+    }
+    (0..text.len()).step_by(2)
+                   .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| format!("Invalid hex {:?}", text)))
+                   .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// PBKDF2-HMAC-SHA256 of "passwd" with a salt of "salt" and one round, from RFC 7914.
+    const PASSWD_HASH: &str = "pbkdf2-sha256$1$73616c74$55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc";
+
+    fn path(route: &str) -> Vec<&str> {
+        route.trim_start_matches('/').split('/').collect()
+    }
+
+    #[test]
+    fn every_route_needs_its_role() {
+        let routes = [
+            ("/exit", Role::Admin),
+            ("/api/v1/exit", Role::Admin),
+
+            ("/truncate", Role::Engineer),
+            ("/stdin.txt", Role::Engineer),
+            ("/audit.json", Role::Engineer),
+            ("/protocol/trace", Role::Engineer),
+            ("/protocol/trace/enable", Role::Engineer),
+            ("/protocol/trace/disable", Role::Engineer),
+            ("/protocol/trace/clear", Role::Engineer),
+            ("/api/v1/logs", Role::Engineer),
+            ("/api/v1/trace", Role::Engineer),
+
+            ("/start", Role::Operator),
+            ("/abort", Role::Operator),
+            ("/scenario", Role::Operator),
+            ("/identity", Role::Operator),
+            ("/identity/clear", Role::Operator),
+            ("/hello", Role::Operator),
+            ("/jig", Role::Operator),
+            ("/scenarios", Role::Operator),
+            ("/tests", Role::Operator),
+            ("/api/v1/start", Role::Operator),
+            ("/api/v1/abort", Role::Operator),
+            ("/api/v1/scenario", Role::Operator),
+            ("/api/v1/identity", Role::Operator),
+            ("/api/v1/unknown", Role::Operator),
+
+            ("/", Role::Viewer),
+            ("/index.html", Role::Viewer),
+            ("/current.json", Role::Viewer),
+            ("/log.json", Role::Viewer),
+            ("/log/current.json", Role::Viewer),
+            ("/log/previous.json", Role::Viewer),
+            ("/protocol/errors.json", Role::Viewer),
+            ("/metrics", Role::Viewer),
+            ("/events", Role::Viewer),
+            ("/timeline.json", Role::Viewer),
+            ("/report.xml", Role::Viewer),
+            ("/report.tap", Role::Viewer),
+            ("/report.csv", Role::Viewer),
+            ("/runs.json", Role::Viewer),
+            ("/runs/1.json", Role::Viewer),
+            ("/runs/1/log.json", Role::Viewer),
+            ("/stdf/run-1.stdf", Role::Viewer),
+        ];
+        for &(route, role) in &routes {
+            assert_eq!(required_role(&path(route)), role, "{}", route);
+        }
+    }
+
+    #[test]
+    fn password_hash_matches_only_its_password() {
+        let hash = PasswordHash::parse(PASSWD_HASH).unwrap();
+        assert_eq!(hash.rounds, 1);
+        assert_eq!(hash.salt, b"salt");
+        assert!(hash.matches("passwd"));
+        assert!(!hash.matches("password"));
+        assert!(!hash.matches(""));
+    }
+
+    #[test]
+    fn hash_password_round_trips() {
+        let hash = PasswordHash::parse(&hash_password("hunter2").unwrap()).unwrap();
+        assert_eq!(hash.rounds, PASSWORD_ROUNDS);
+        assert!(hash.matches("hunter2"));
+        assert!(!hash.matches("hunter3"));
+    }
+
+    #[test]
+    fn password_hash_rejects_malformed_hashes() {
+        for text in &["", "passwd", "sha256$1$73616c74$00", "pbkdf2-sha256$1$73616c74",
+                      "pbkdf2-sha256$one$73616c74$00", "pbkdf2-sha256$1$salt$00",
+                      "pbkdf2-sha256$1$73616c74$00$00"] {
+            assert!(PasswordHash::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn from_hex_decodes_only_hex() {
+        assert_eq!(from_hex("").unwrap(), b"");
+        assert_eq!(from_hex("00ff7A").unwrap(), [0x00, 0xff, 0x7a]);
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+        assert!(from_hex("+1").is_err());
+        // Two bytes long, but not two hex digits.  This mustn't panic on the char boundary.
+        assert!(from_hex("é").is_err());
+        assert_eq!(from_hex(&to_hex(b"\x01\x23\xfe")).unwrap(), b"\x01\x23\xfe");
+    }
+
+    #[test]
+    fn same_bytes_compares_whole_secrets() {
+        assert!(same_bytes(b"", b""));
+        assert!(same_bytes(b"secret", b"secret"));
+        assert!(!same_bytes(b"secret", b"secreT"));
+        assert!(!same_bytes(b"secret", b"secret!"));
+        assert!(!same_bytes(b"secret", b""));
+    }
+
+    #[test]
+    fn check_enforces_roles() {
+        let mut users = HashMap::new();
+        users.insert("admin".to_string(), User { hash: PasswordHash::parse(PASSWD_HASH).unwrap(), role: Role::Admin });
+        let auth = Auth {
+            anonymous: Some(Role::Viewer),
+            users,
+            tokens: vec![Token { name: "line".to_string(), sha256: Sha256::digest(b"t0ken").to_vec(), role: Role::Operator }],
+            verified: Mutex::new(HashMap::new()),
+        };
+        let anonymous = Headers::new();
+        assert_eq!(auth.check(&anonymous, None, Role::Viewer).unwrap().name, "anonymous");
+        assert!(matches!(auth.check(&anonymous, None, Role::Operator), Err(Refusal::Forbidden(_))));
+
+        let caller = auth.check(&anonymous, Some("t0ken"), Role::Operator).unwrap();
+        assert_eq!((caller.name.as_str(), caller.role), ("line", Role::Operator));
+        assert!(matches!(auth.check(&anonymous, Some("t0ken"), Role::Engineer), Err(Refusal::Forbidden(_))));
+        assert!(matches!(auth.check(&anonymous, Some("wrong"), Role::Viewer), Err(Refusal::Unauthorized(_))));
+
+        let mut bearer = Headers::new();
+        bearer.set_raw("Authorization", vec![b"Bearer t0ken".to_vec()]);
+        assert_eq!(auth.check(&bearer, None, Role::Operator).unwrap().role, Role::Operator);
+
+        let mut basic = Headers::new();
+        basic.set_raw("Authorization", vec![b"Basic YWRtaW46cGFzc3dk".to_vec()]);
+        assert_eq!(auth.check(&basic, None, Role::Admin).unwrap().name, "admin");
+        // Again, now that the password has been checked once.
+        assert_eq!(auth.check(&basic, None, Role::Admin).unwrap().name, "admin");
+
+        let mut wrong = Headers::new();
+        wrong.set_raw("Authorization", vec![b"Basic YWRtaW46d3Jvbmc=".to_vec()]);
+        assert!(matches!(auth.check(&wrong, None, Role::Viewer), Err(Refusal::Unauthorized(_))));
+    }
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use events::{StateEvent, Subscription};
+use super::{cfti_send, ConnectionStatus, OutgoingMessage, SERVER_SIGNATURE};
+
+/// How long to wait for each reply before asking again.
+const REPLY_TIMEOUT_SECS: u64 = 5;
+
+/// How many times to ask for each piece of state before giving up on it.
+const ATTEMPTS: u32 = 5;
+
+/// Why a handshake ended early.
+enum Stop {
+    /// The server went away, so there is nobody to ask.
+    Disconnected,
+
+    /// The server never answered this request.
+    NoReply(&'static str),
+}
+
+fn is_disconnect(event: &StateEvent) -> bool {
+    matches!(event, StateEvent::Connection { connection: ConnectionStatus::Disconnected { .. } })
+}
+
+/// Send `message` until the server answers with an event that `wanted` accepts.
+fn request(subscription: &Subscription, message: OutgoingMessage, reply: &'static str, wanted: fn(&StateEvent) -> bool) -> Result<StateEvent, Stop> {
+    for _ in 0..ATTEMPTS {
+        cfti_send(message.clone());
+        match subscription.wait_for(Duration::from_secs(REPLY_TIMEOUT_SECS), |e| wanted(e) || is_disconnect(e)) {
+            Some(ref e) if is_disconnect(e) => return Err(Stop::Disconnected),
+            Some(e) => return Ok(e),
+            None => eprintln!("No {} from the server after {} seconds, asking again", reply, REPLY_TIMEOUT_SECS),
+        }
+    }
+    Err(Stop::NoReply(reply))
+}
+
+/// Introduce ourselves, then ask for everything /current.json shows.
+/// Returns the jig the server reported.
+fn handshake(subscription: &Subscription) -> Result<String, Stop> {
+    cfti_send(OutgoingMessage::Hello(SERVER_SIGNATURE.to_string()));
+
+    let jig = match request(subscription, OutgoingMessage::GetJig, "JIG", |e| matches!(e, StateEvent::Jig { .. }))? {
+        StateEvent::Jig { jig } => jig,
+        _ => unreachable!(),
+    };
+    request(subscription, OutgoingMessage::Scenarios, "SCENARIOS", |e| matches!(e, StateEvent::Scenarios { .. }))?;
+    request(subscription, OutgoingMessage::GetTests, "TESTS", |e| matches!(e, StateEvent::Tests { .. }))?;
+    Ok(jig)
+}
+
+/// Fill in the interface state on startup, or once the server first connects
+/// if it isn't `connected` yet, and again whenever it reconnects or reports
+/// a different jig.  `subscription` must be an internal one, so that no
+/// reconnection is missed, and must be taken before the server is first
+/// heard from.
+pub fn run(subscription: Subscription, connected: bool) {
+    let mut jig = None;
+    let mut again = connected;
+    loop {
+        if again {
+            match handshake(&subscription) {
+                Ok(j) => jig = Some(j),
+                Err(Stop::Disconnected) => (),
+                Err(Stop::NoReply(reply)) => eprintln!("Gave up waiting for {} from the server", reply),
+            }
+        }
+
+        let event = match subscription.receiver.recv() {
+            Ok((_, event)) => event,
+            Err(_) => return,
+        };
+        again = match event {
+            StateEvent::Connection { connection: ConnectionStatus::Connected { .. } } => true,
+            StateEvent::Jig { jig: ref new } if jig.as_ref() != Some(new) => {
+                // Don't go round again if the server stops answering JIG.
+                jig = Some(new.clone());
+                true
+            },
+            _ => false,
+        };
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time;
+
+use super::{cfti_unescape, unix_time};
+
+/// Number of rejected lines to remember for /protocol/errors.json.
+const RECENT_ERROR_COUNT: usize = 100;
+
+/// Result given to a FINISH whose result code can't be read, which counts as a failure.
+const UNREADABLE_RESULT: u32 = 500;
+
+/// Which kind of object a DESCRIBE line refers to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DescribeClass {
+    Jig,
+    Scenario,
+    Test,
+}
+
+/// Which property a DESCRIBE line sets.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DescribeField {
+    Name,
+    Description,
+}
+
+impl DescribeClass {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DescribeClass::Jig => "jig",
+            DescribeClass::Scenario => "scenario",
+            DescribeClass::Test => "test",
+        }
+    }
+}
+
+impl DescribeField {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DescribeField::Name => "name",
+            DescribeField::Description => "description",
+        }
+    }
+}
+
+/// A message received from the server, the counterpart to OutgoingMessage.
+#[derive(Clone, Debug)]
+pub enum IncomingMessage {
+    /// HELLO [server-identifier]
+    Hello(String),
+
+    /// JIG [jig-id]
+    Jig(String),
+
+    /// SCENARIOS [scenario-id] [scenario-id] ...
+    Scenarios(Vec<String>),
+
+    /// SCENARIO [scenario-id]
+    Scenario(String),
+
+    /// TESTS [scenario-id] [test-id] [test-id] ...
+    Tests(String /*scenario*/, Vec<String> /*tests*/),
+
+    /// DESCRIBE [class] [field] [item-id] [value]
+    Describe(DescribeClass, DescribeField, String /*name*/, String /*value*/),
+
+    /// PING [token]
+    Ping(String),
+
+    /// START [scenario-id]
+    Start(String),
+
+    /// FINISH [scenario-id] [result] [reason]
+    Finish(String /*scenario*/, u32 /*result*/, String /*reason*/),
+
+    /// RUNNING [test-id]
+    Running(String),
+
+    /// PASS [test-id] [message]
+    Pass(String, String),
+
+    /// FAIL [test-id] [reason]
+    Fail(String, String),
+
+    /// SKIP [test-id] [reason]
+    Skip(String, String),
+
+    /// LOG [message-type] [unit] [unit-type] [unix-time-secs] [unix-time-nsecs] [message]
+    Log(String /*message_class*/, String /*unit_id*/, String /*unit_type*/, time::Duration, String /*message*/),
+
+    /// EXIT
+    Exit,
+}
+
+/// The reason a line from the server could not be understood.
+#[derive(Clone, Debug, Serialize)]
+pub enum ProtocolError {
+    /// The line contained no words at all.
+    EmptyLine,
+
+    /// The line was not valid UTF-8.
+    InvalidEncoding,
+
+    /// The first word of the line was not a known verb.
+    UnknownVerb(String),
+
+    /// A required argument was not present.
+    MissingArgument { verb: String, argument: String },
+
+    /// An argument was supposed to be a number, but wasn't.
+    InvalidNumber { verb: String, argument: String, value: String },
+
+    /// A number was too large for its argument.
+    NumberOutOfRange { verb: String, argument: String, value: String },
+
+    /// DESCRIBE referred to an unknown class of object.
+    UnknownDescribeClass(String),
+
+    /// DESCRIBE referred to an unknown field.
+    UnknownDescribeField(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolError::EmptyLine => write!(f, "empty line"),
+            ProtocolError::InvalidEncoding => write!(f, "line is not valid UTF-8"),
+            ProtocolError::UnknownVerb(ref v) => write!(f, "unrecognized command: {}", v),
+            ProtocolError::MissingArgument { ref verb, ref argument } => write!(f, "{} is missing its {} argument", verb, argument),
+            ProtocolError::InvalidNumber { ref verb, ref argument, ref value } => write!(f, "{} argument {} is not a number: {}", verb, argument, value),
+            ProtocolError::NumberOutOfRange { ref verb, ref argument, ref value } => write!(f, "{} argument {} is out of range: {}", verb, argument, value),
+            ProtocolError::UnknownDescribeClass(ref c) => write!(f, "unrecognized DESCRIBE class: {}", c),
+            ProtocolError::UnknownDescribeField(ref c) => write!(f, "unrecognized DESCRIBE field: {}", c),
+        }
+    }
+}
+
+/// Walks the words of a line, producing errors that name the missing argument.
+struct Arguments {
+    verb: String,
+    items: VecDeque<String>,
+}
+
+impl Arguments {
+    fn next(&mut self, argument: &str) -> Result<String, ProtocolError> {
+        match self.items.pop_front() {
+            Some(s) => Ok(s),
+            None => Err(ProtocolError::MissingArgument {
+                verb: self.verb.clone(),
+                argument: argument.to_string(),
+            }),
+        }
+    }
+
+    fn next_or(&mut self, default: &str) -> String {
+        self.items.pop_front().unwrap_or_else(|| default.to_string())
+    }
+
+    fn next_number<T: ::std::str::FromStr>(&mut self, argument: &str) -> Result<T, ProtocolError> {
+        let value = self.next(argument)?;
+        match value.parse() {
+            Ok(n) => Ok(n),
+            Err(_) => Err(ProtocolError::InvalidNumber {
+                verb: self.verb.clone(),
+                argument: argument.to_string(),
+                value,
+            }),
+        }
+    }
+
+    fn rest(&mut self) -> Vec<String> {
+        self.items.drain(..).collect()
+    }
+
+    fn rest_joined(&mut self) -> String {
+        self.rest().join(" ")
+    }
+}
+
+impl IncomingMessage {
+    /// Parse one line received from the server.  Some mistakes can be worked
+    /// around, in which case the message comes back along with the error,
+    /// which should still be reported.
+    pub fn parse(line: &str) -> Result<(IncomingMessage, Option<ProtocolError>), ProtocolError> {
+        let mut items: VecDeque<String> = line.split_whitespace().map(|x| cfti_unescape(x.to_string())).collect();
+        let verb = match items.pop_front() {
+            Some(v) => v.to_lowercase(),
+            None => return Err(ProtocolError::EmptyLine),
+        };
+        let mut args = Arguments { verb: verb.to_uppercase(), items };
+
+        let message = match verb.as_str() {
+            "hello" => IncomingMessage::Hello(args.rest_joined()),
+            "jig" => IncomingMessage::Jig(args.next_or("No Jig")),
+            "scenarios" => IncomingMessage::Scenarios(args.rest()),
+            "scenario" => IncomingMessage::Scenario(args.next_or("No Scenario")),
+            "tests" => {
+                let scenario = args.next("scenario")?;
+                IncomingMessage::Tests(scenario, args.rest())
+            },
+            "describe" => {
+                let class = match args.next("class")?.to_lowercase().as_str() {
+                    "jig" => DescribeClass::Jig,
+                    "scenario" => DescribeClass::Scenario,
+                    "test" => DescribeClass::Test,
+                    other => return Err(ProtocolError::UnknownDescribeClass(other.to_string())),
+                };
+                let field = match args.next("field")?.to_lowercase().as_str() {
+                    "name" => DescribeField::Name,
+                    "description" => DescribeField::Description,
+                    other => return Err(ProtocolError::UnknownDescribeField(other.to_string())),
+                };
+                let name = args.next_or("No Name");
+                IncomingMessage::Describe(class, field, name, args.rest_joined())
+            },
+            "ping" => IncomingMessage::Ping(args.next_or("")),
+            "start" => IncomingMessage::Start(args.next("scenario")?),
+            "finish" => {
+                let scenario = args.next("scenario")?;
+
+                // Still finish the run, as a failure, rather than leaving it running forever.
+                let (result, error) = match args.next_number("result") {
+                    Ok(result) => (result, None),
+                    Err(e) => (UNREADABLE_RESULT, Some(e)),
+                };
+                return Ok((IncomingMessage::Finish(scenario, result, args.rest_joined()), error));
+            },
+            "running" => IncomingMessage::Running(args.next("test")?),
+            "pass" => {
+                let test = args.next("test")?;
+                IncomingMessage::Pass(test, args.rest_joined())
+            },
+            "fail" => {
+                let test = args.next("test")?;
+                IncomingMessage::Fail(test, args.rest_joined())
+            },
+            "skip" => {
+                let test = args.next("test")?;
+                IncomingMessage::Skip(test, args.rest_joined())
+            },
+            "log" => {
+                let message_class = args.next("message-type")?;
+                let unit_id = args.next("unit")?;
+                let unit_type = args.next("unit-type")?;
+                let secs = args.next_number("unix-time-secs")?;
+                let nsecs: u32 = args.next_number("unix-time-nsecs")?;
+                if nsecs >= 1_000_000_000 {
+                    return Err(ProtocolError::NumberOutOfRange {
+                        verb: args.verb.clone(),
+                        argument: "unix-time-nsecs".to_string(),
+                        value: nsecs.to_string(),
+                    });
+                }
+                IncomingMessage::Log(message_class, unit_id, unit_type, time::Duration::new(secs, nsecs), args.rest_joined())
+            },
+            "exit" => IncomingMessage::Exit,
+            _ => return Err(ProtocolError::UnknownVerb(verb)),
+        };
+        Ok((message, None))
+    }
+}
+
+/// A line that was received from the server but could not be parsed.
+#[derive(Clone, Debug, Serialize)]
+pub struct RejectedLine {
+    /// When the line was received, since the Unix epoch.
+    timestamp: time::Duration,
+
+    /// The line exactly as it was received.
+    line: String,
+
+    /// What was wrong with it.
+    error: ProtocolError,
+
+    /// Human-readable version of `error`.
+    message: String,
+}
+
+/// Count of every rejected line, along with the most recent ones.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProtocolErrors {
+    count: u64,
+    recent: VecDeque<RejectedLine>,
+}
+
+impl ProtocolErrors {
+    pub fn new() -> ProtocolErrors {
+        ProtocolErrors {
+            count: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Number of lines rejected since startup.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn record(&mut self, line: &str, error: ProtocolError) {
+        self.count += 1;
+        self.recent.push_back(RejectedLine {
+            timestamp: unix_time(),
+            line: line.to_string(),
+            message: error.to_string(),
+            error,
+        });
+        while self.recent.len() > RECENT_ERROR_COUNT {
+            self.recent.pop_front();
+        }
+    }
+}
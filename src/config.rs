@@ -0,0 +1,378 @@
+use clap::ArgMatches;
+
+use std::fs::File;
+use std::io::Read;
+use std::ops::RangeInclusive;
+
+use toml;
+
+use super::DisconnectPolicy;
+
+/// Settings read from the --config file.  Every one of them can also be
+/// given on the command line, which takes precedence.  Anything missing
+/// from both gets the same default as before there was a config file.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub server: ServerConfig,
+
+    #[serde(default)]
+    pub exclave: ExclaveConfig,
+
+    #[serde(default)]
+    pub logs: LogsConfig,
+
+    #[serde(default)]
+    pub storage: StorageConfig,
+
+    #[serde(default)]
+    pub auth: AuthConfig,
+
+    #[serde(default)]
+    pub tls: TlsConfig,
+
+    #[serde(default)]
+    pub exporters: ExportersConfig,
+
+    #[serde(default)]
+    pub station: Station,
+
+    #[serde(default)]
+    pub units: UnitsConfig,
+
+    #[serde(default)]
+    pub results: ResultsConfig,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Interface address to listen on.
+    #[serde(default = "default_address")]
+    pub address: String,
+
+    #[serde(default = "default_port")]
+    pub port: u16,
+
+    /// The WebSocket server is only started when this is given.
+    #[serde(default)]
+    pub ws_port: Option<u16>,
+
+    /// Directory that the web pages are served from.
+    #[serde(default = "default_html_root")]
+    pub html_root: String,
+
+    #[serde(default)]
+    pub legacy_get: bool,
+
+    /// "exit" or "stay", as for --on-disconnect.
+    #[serde(default = "default_on_disconnect")]
+    pub on_disconnect: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            address: default_address(),
+            port: default_port(),
+            ws_port: None,
+            html_root: default_html_root(),
+            legacy_get: false,
+            on_disconnect: default_on_disconnect(),
+        }
+    }
+}
+
+/// How to reach exclave.  At most one of `command` and `transport` may be
+/// given, and neither is used with --simulate or --replay.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExclaveConfig {
+    /// Command line to start exclave with, as for --exclave.
+    #[serde(default)]
+    pub command: Option<String>,
+
+    /// Tests directory to run `command` in.
+    #[serde(default)]
+    pub tests: Option<String>,
+
+    /// As for --transport.  Defaults to stdio.
+    #[serde(default)]
+    pub transport: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogsConfig {
+    /// Most log messages to keep in memory.
+    #[serde(default = "default_memory_count")]
+    pub memory_count: usize,
+
+    /// Most memory to use for log messages.
+    #[serde(default = "default_memory_bytes")]
+    pub memory_bytes: usize,
+
+    #[serde(default)]
+    pub spill_dir: Option<String>,
+
+    /// Keep every line received for /stdin.txt.
+    #[serde(default)]
+    pub log_stdin: bool,
+
+    /// Start with protocol tracing turned on.
+    #[serde(default)]
+    pub trace: bool,
+
+    #[serde(default = "default_trace_entries")]
+    pub trace_entries: usize,
+}
+
+impl Default for LogsConfig {
+    fn default() -> LogsConfig {
+        LogsConfig {
+            memory_count: default_memory_count(),
+            memory_bytes: default_memory_bytes(),
+            spill_dir: None,
+            log_stdin: false,
+            trace: false,
+            trace_entries: default_trace_entries(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageConfig {
+    /// File to keep every run in, across restarts.
+    #[serde(default)]
+    pub history: Option<String>,
+
+    #[serde(default)]
+    pub audit_log: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// The JSON file of users, tokens, and roles.
+    #[serde(default)]
+    pub file: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    #[serde(default)]
+    pub cert: Option<String>,
+
+    #[serde(default)]
+    pub key: Option<String>,
+
+    #[serde(default)]
+    pub client_ca: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportersConfig {
+    #[serde(default)]
+    pub stdf_dir: Option<String>,
+
+    #[serde(default)]
+    pub stdf_lot: Option<String>,
+}
+
+/// Which test station this is, so that runs from several stations can be
+/// told apart once they are collected together.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Station {
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Written to STDF files as the station number.
+    #[serde(default)]
+    pub number: Option<u8>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UnitsConfig {
+    /// Regular expression that serial numbers must match in full.
+    #[serde(default)]
+    pub serial_pattern: Option<String>,
+}
+
+/// Which FINISH result codes count as a pass.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResultsConfig {
+    #[serde(default = "default_pass_min")]
+    pub pass_min: u32,
+
+    #[serde(default = "default_pass_max")]
+    pub pass_max: u32,
+}
+
+impl Default for ResultsConfig {
+    fn default() -> ResultsConfig {
+        ResultsConfig {
+            pass_min: default_pass_min(),
+            pass_max: default_pass_max(),
+        }
+    }
+}
+
+fn default_address() -> String { "0.0.0.0".to_string() }
+fn default_port() -> u16 { 3000 }
+fn default_html_root() -> String { "html".to_string() }
+fn default_on_disconnect() -> String { "stay".to_string() }
+fn default_memory_count() -> usize { 100_000 }
+fn default_memory_bytes() -> usize { 64 * 1024 * 1024 }
+fn default_trace_entries() -> usize { 10_000 }
+fn default_pass_min() -> u32 { 200 }
+fn default_pass_max() -> u32 { 299 }
+
+fn set_string(matches: &ArgMatches, name: &str, setting: &mut String) {
+    if let Some(value) = matches.value_of(name) {
+        *setting = value.to_string();
+    }
+}
+
+fn set_optional(matches: &ArgMatches, name: &str, setting: &mut Option<String>) {
+    if let Some(value) = matches.value_of(name) {
+        *setting = Some(value.to_string());
+    }
+}
+
+fn set_flag(matches: &ArgMatches, name: &str, setting: &mut bool) {
+    if matches.is_present(name) {
+        *setting = true;
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, String> {
+        let mut text = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| e.to_string())?;
+        toml::from_str(&text).map_err(|e| e.to_string())
+    }
+
+    /// Replace the file's settings with any that were given on the command line.
+    pub fn apply_arguments(&mut self, matches: &ArgMatches) {
+        set_string(matches, "ADDRESS", &mut self.server.address);
+        if matches.is_present("PORT") {
+            self.server.port = value_t_or_exit!(matches, "PORT", u16);
+        }
+        if matches.is_present("WS_PORT") {
+            self.server.ws_port = Some(value_t_or_exit!(matches, "WS_PORT", u16));
+        }
+        set_string(matches, "HTML_ROOT", &mut self.server.html_root);
+        set_flag(matches, "LEGACY_GET", &mut self.server.legacy_get);
+        set_string(matches, "ON_DISCONNECT", &mut self.server.on_disconnect);
+
+        // Each way of reaching exclave on the command line replaces whichever
+        // one the file has.
+        if matches.is_present("SIMULATE") || matches.is_present("REPLAY") {
+            self.exclave = ExclaveConfig::default();
+        } else if matches.is_present("EXCLAVE") {
+            self.exclave.transport = None;
+        } else if matches.is_present("TRANSPORT") {
+            self.exclave.command = None;
+            self.exclave.tests = None;
+        }
+        set_optional(matches, "EXCLAVE", &mut self.exclave.command);
+        set_optional(matches, "EXCLAVE_TESTS", &mut self.exclave.tests);
+        set_optional(matches, "TRANSPORT", &mut self.exclave.transport);
+
+        if matches.is_present("LOG_MEMORY_COUNT") {
+            self.logs.memory_count = value_t_or_exit!(matches, "LOG_MEMORY_COUNT", usize);
+        }
+        if matches.is_present("LOG_MEMORY_BYTES") {
+            self.logs.memory_bytes = value_t_or_exit!(matches, "LOG_MEMORY_BYTES", usize);
+        }
+        set_optional(matches, "LOG_SPILL_DIR", &mut self.logs.spill_dir);
+        set_flag(matches, "LOG_STDIN", &mut self.logs.log_stdin);
+        set_flag(matches, "TRACE", &mut self.logs.trace);
+        if matches.is_present("TRACE_ENTRIES") {
+            self.logs.trace_entries = value_t_or_exit!(matches, "TRACE_ENTRIES", usize);
+        }
+
+        set_optional(matches, "HISTORY", &mut self.storage.history);
+        set_optional(matches, "AUDIT_LOG", &mut self.storage.audit_log);
+        set_optional(matches, "AUTH", &mut self.auth.file);
+        set_optional(matches, "TLS_CERT", &mut self.tls.cert);
+        set_optional(matches, "TLS_KEY", &mut self.tls.key);
+        set_optional(matches, "TLS_CLIENT_CA", &mut self.tls.client_ca);
+        set_optional(matches, "STDF_DIR", &mut self.exporters.stdf_dir);
+        set_optional(matches, "STDF_LOT", &mut self.exporters.stdf_lot);
+
+        set_optional(matches, "STATION_NAME", &mut self.station.name);
+        if matches.is_present("STATION_NUMBER") {
+            self.station.number = Some(value_t_or_exit!(matches, "STATION_NUMBER", u8));
+        }
+        set_optional(matches, "SERIAL_PATTERN", &mut self.units.serial_pattern);
+        if matches.is_present("PASS_MIN") {
+            self.results.pass_min = value_t_or_exit!(matches, "PASS_MIN", u32);
+        }
+        if matches.is_present("PASS_MAX") {
+            self.results.pass_max = value_t_or_exit!(matches, "PASS_MAX", u32);
+        }
+    }
+
+    /// Make sure the settings make sense together.  The command line alone
+    /// is already checked by clap, but the file may say anything.
+    pub fn check(&self) -> Result<(), String> {
+        if self.server.on_disconnect != "exit" && self.server.on_disconnect != "stay" {
+            return Err(format!("Unknown disconnect policy {:?}: it must be exit or stay", self.server.on_disconnect));
+        }
+        // The socket transports exist to wait for exclave to come back, which
+        // quitting on the first disconnect would defeat.
+        if self.server.on_disconnect == "exit" && self.exclave.transport.as_ref().is_some_and(|t| t != "stdio") {
+            return Err("The exit disconnect policy can only be used with stdio, --exclave, --simulate, or --replay, since the socket transports wait for exclave to reconnect".to_string());
+        }
+        if self.exclave.command.is_some() && self.exclave.transport.is_some() {
+            return Err("Only one of an exclave command and a transport may be given".to_string());
+        }
+        if self.exclave.tests.is_some() && self.exclave.command.is_none() {
+            return Err("An exclave tests directory needs an exclave command to run in it".to_string());
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            return Err("A TLS certificate and key must be given together".to_string());
+        }
+        if self.tls.client_ca.is_some() && self.tls.cert.is_none() {
+            return Err("A TLS client CA needs a TLS certificate and key".to_string());
+        }
+        if self.server.ws_port.is_some() && self.tls.cert.is_some() {
+            return Err("The WebSocket server doesn't support TLS, so a WebSocket port can't be used with a TLS certificate".to_string());
+        }
+        if self.server.ws_port == Some(self.server.port) {
+            return Err(format!("The WebSocket server can't share port {} with the HTTP server", self.server.port));
+        }
+        if self.exporters.stdf_lot.is_some() && self.exporters.stdf_dir.is_none() {
+            return Err("An STDF lot needs an STDF directory".to_string());
+        }
+        if self.results.pass_min > self.results.pass_max {
+            return Err(format!("The lowest passing result, {}, is above the highest, {}", self.results.pass_min, self.results.pass_max));
+        }
+        Ok(())
+    }
+
+    pub fn disconnect_policy(&self) -> DisconnectPolicy {
+        match self.server.on_disconnect.as_str() {
+            "exit" => DisconnectPolicy::Exit,
+            _ => DisconnectPolicy::Stay,
+        }
+    }
+
+    /// FINISH result codes that count as a pass.
+    pub fn pass_codes(&self) -> RangeInclusive<u32> {
+        self.results.pass_min..=self.results.pass_max
+    }
+
+    /// The settings as a config file, for --print-config.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap()
+    }
+}
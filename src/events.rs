@@ -0,0 +1,305 @@
+use iron::prelude::*;
+use iron::status;
+use iron::mime::Mime;
+use iron::headers::{CacheControl, CacheDirective};
+use iron::response::WriteBody;
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender, Receiver, RecvTimeoutError, TrySendError};
+use std::time::{Duration, Instant};
+
+use serde_json;
+
+use identity::DutIdentity;
+use super::{ConnectionStatus, LogMessage, ScenarioState, TestResult, TestTiming};
+
+/// Number of events kept around so that reconnecting clients can catch up.
+const EVENT_HISTORY_LENGTH: usize = 4096;
+
+/// How long a stream may sit idle before a keepalive comment is sent.
+/// This is also how dead clients get noticed, since writing to them fails.
+const KEEPALIVE_INTERVAL_SECS: u64 = 15;
+
+/// Reconnection delay suggested to browsers, in milliseconds.
+const RETRY_MS: u32 = 2000;
+
+/// Events that may be waiting for a listener before it is dropped for
+/// falling behind.  A dropped /events client gets a reset when it reconnects.
+const SUBSCRIBER_QUEUE_LENGTH: usize = 1024;
+
+/// Most /events streams open at once.  Each one holds an HTTP worker
+/// thread for as long as it lasts, so main adds this many to the pool.
+pub const MAX_EVENT_STREAMS: usize = 32;
+
+/// Number of /events streams open right now.
+static OPEN_STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+/// A single change to the interface state, as applied by stdin_monitor.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StateEvent {
+    /// HELLO was received, identifying the server.
+    Hello { server: String },
+
+    /// JIG was received with the current jig identifier.
+    Jig { jig: String },
+
+    /// SCENARIOS was received with a new list of scenarios.
+    Scenarios { scenarios: Vec<String> },
+
+    /// A new scenario was selected.
+    Scenario { scenario: String },
+
+    /// TESTS was received with the list of tests for a scenario.
+    Tests { scenario: String, tests: Vec<String> },
+
+    /// DESCRIBE updated the name or description of a jig, scenario, or test.
+    Describe { class: String, field: String, name: String, value: String },
+
+    /// The identity of the next unit to be tested was set or cleared.
+    Identity { identity: Option<DutIdentity> },
+
+    /// A scenario was started.
+    Start { scenario: String },
+
+    /// A scenario finished with the given result code, after running for `duration`.
+    Finish { scenario: String, result: u32, scenario_state: ScenarioState, duration: Option<Duration> },
+
+    /// A test changed state due to RUNNING, PASS, FAIL, or SKIP.
+    TestResult { test: String, result: TestResult, timing: TestTiming },
+
+    /// A new log message arrived.
+    Log { log: LogMessage },
+
+    /// The connection to the server was made or lost.
+    Connection { connection: ConnectionStatus },
+}
+
+impl StateEvent {
+    /// The name used for the "event:" field of the SSE stream.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            StateEvent::Hello { .. } => "hello",
+            StateEvent::Jig { .. } => "jig",
+            StateEvent::Scenarios { .. } => "scenarios",
+            StateEvent::Scenario { .. } => "scenario",
+            StateEvent::Tests { .. } => "tests",
+            StateEvent::Describe { .. } => "describe",
+            StateEvent::Identity { .. } => "identity",
+            StateEvent::Start { .. } => "start",
+            StateEvent::Finish { .. } => "finish",
+            StateEvent::TestResult { .. } => "test_result",
+            StateEvent::Log { .. } => "log",
+            StateEvent::Connection { .. } => "connection",
+        }
+    }
+}
+
+/// A new listener on the event bus.
+pub struct Subscription {
+    /// Events the listener missed since the ID it last saw, or None if they
+    /// are no longer available and the listener must reload its full state.
+    pub missed: Option<Vec<(u64, StateEvent)>>,
+
+    /// Newly-published events.
+    pub receiver: Receiver<(u64, StateEvent)>,
+}
+
+impl Subscription {
+    /// Wait up to `timeout` for a newly-published event that `wanted`
+    /// accepts, skipping any others.
+    pub fn wait_for<F: Fn(&StateEvent) -> bool>(&self, timeout: Duration, wanted: F) -> Option<StateEvent> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.checked_duration_since(Instant::now())?;
+            match self.receiver.recv_timeout(remaining) {
+                Ok((_, event)) => if wanted(&event) { return Some(event) },
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+/// Where a listener's events are sent.
+enum Listener {
+    /// A client, which is dropped if it falls SUBSCRIBER_QUEUE_LENGTH events behind.
+    Client(SyncSender<(u64, StateEvent)>),
+
+    /// Part of this program, which is never dropped.
+    Internal(Sender<(u64, StateEvent)>),
+}
+
+/// Fans state changes out to every listener, and remembers recent ones.
+pub struct EventBus {
+    /// ID that will be assigned to the next event.  IDs start at 1.
+    next_id: u64,
+
+    /// The most recent events, oldest first.
+    history: VecDeque<(u64, StateEvent)>,
+
+    /// Currently-connected listeners.
+    subscribers: Vec<Listener>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus {
+            next_id: 1,
+            history: VecDeque::new(),
+            subscribers: vec![],
+        }
+    }
+
+    /// ID of the most recently-published event, or 0 if there have been none.
+    pub fn last_id(&self) -> u64 {
+        self.next_id - 1
+    }
+
+    pub fn publish(&mut self, event: StateEvent) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.history.push_back((id, event.clone()));
+        while self.history.len() > EVENT_HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+
+        // Listeners that have gone away will have dropped their receiver.
+        // Ones that have fallen too far behind are dropped here, rather than
+        // queueing up events for them without limit.
+        self.subscribers.retain(|s| match *s {
+            Listener::Client(ref tx) => match tx.try_send((id, event.clone())) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    eprintln!("Dropping an event listener that is {} events behind", SUBSCRIBER_QUEUE_LENGTH);
+                    false
+                },
+                Err(TrySendError::Disconnected(_)) => false,
+            },
+            Listener::Internal(ref tx) => tx.send((id, event.clone())).is_ok(),
+        });
+    }
+
+    /// Start listening for events.  If `last_event_id` is given, every
+    /// event after that one is returned in `missed`.  The receiver is
+    /// disconnected if the listener falls too far behind.
+    pub fn subscribe(&mut self, last_event_id: Option<u64>) -> Subscription {
+        let (tx, rx) = sync_channel(SUBSCRIBER_QUEUE_LENGTH);
+        self.subscribers.push(Listener::Client(tx));
+
+        let oldest_id = match self.history.front() {
+            Some(&(id, _)) => id,
+            None => self.next_id,
+        };
+
+        let missed = match last_event_id {
+            None => Some(vec![]),
+
+            // The listener saw an ID we never issued, so it must have been
+            // talking to a previous instance of this server.
+            Some(last) if last >= self.next_id => None,
+
+            // Some of the events it needs have already been discarded.
+            Some(last) if last + 1 < oldest_id => None,
+
+            Some(last) => Some(self.history.iter().filter(|&&(id, _)| id > last).cloned().collect()),
+        };
+
+        Subscription {
+            missed,
+            receiver: rx,
+        }
+    }
+
+    /// Start listening for events from now on, without ever being dropped.
+    /// Only for parts of this program that keep reading what they are sent,
+    /// since nothing limits how many events may queue up for them.
+    pub fn subscribe_internal(&mut self) -> Subscription {
+        let (tx, rx) = channel();
+        self.subscribers.push(Listener::Internal(tx));
+        Subscription {
+            missed: Some(vec![]),
+            receiver: rx,
+        }
+    }
+}
+
+struct EventStream {
+    /// Event ID to report alongside a "reset" event, or None if the client
+    /// is able to resume where it left off.
+    reset_id: Option<u64>,
+    backlog: Vec<(u64, StateEvent)>,
+    receiver: Receiver<(u64, StateEvent)>,
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn write_event(res: &mut dyn Write, id: u64, event: &StateEvent) -> io::Result<()> {
+    write!(res, "id: {}\nevent: {}\ndata: {}\n\n", id, event.name(), serde_json::to_string(event).unwrap())
+}
+
+impl WriteBody for EventStream {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        write!(res, "retry: {}\n\n", RETRY_MS)?;
+
+        // Tell the client that it has missed events and must reload everything.
+        if let Some(id) = self.reset_id {
+            write!(res, "id: {}\nevent: reset\ndata: {{}}\n\n", id)?;
+        }
+
+        for (id, event) in self.backlog.drain(..) {
+            write_event(res, id, &event)?;
+        }
+        res.flush()?;
+
+        loop {
+            match self.receiver.recv_timeout(Duration::from_secs(KEEPALIVE_INTERVAL_SECS)) {
+                Ok((id, event)) => write_event(res, id, &event)?,
+                Err(RecvTimeoutError::Timeout) => write!(res, ": keepalive\n\n")?,
+                // Dropped for falling behind.  The browser will reconnect and
+                // be told to reset.
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            res.flush()?;
+        }
+    }
+}
+
+pub fn show_events(request: &mut Request, events: &Arc<Mutex<EventBus>>) -> IronResult<Response> {
+    let last_event_id = request.headers.get_raw("Last-Event-ID")
+                                       .and_then(|v| v.first())
+                                       .and_then(|v| String::from_utf8_lossy(v).trim().parse().ok());
+
+    if OPEN_STREAMS.fetch_add(1, Ordering::SeqCst) >= MAX_EVENT_STREAMS {
+        OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+        let mut response = Response::with((status::ServiceUnavailable,
+                                           format!("Too many event streams are open (at most {} are allowed)", MAX_EVENT_STREAMS)));
+        response.headers.set_raw("Retry-After", vec![b"10".to_vec()]);
+        return Ok(response);
+    }
+
+    let mut events = events.lock().unwrap();
+    let subscription = events.subscribe(last_event_id);
+    let (reset_id, backlog) = match subscription.missed {
+        Some(missed) => (None, missed),
+        None => (Some(events.last_id()), vec![]),
+    };
+
+    let stream = EventStream {
+        reset_id,
+        backlog,
+        receiver: subscription.receiver,
+    };
+
+    let content_type = "text/event-stream".parse::<Mime>().unwrap();
+    let mut response = Response::with((content_type, status::Ok, Box::new(stream) as Box<dyn WriteBody>));
+    response.headers.set(CacheControl(vec![CacheDirective::NoCache]));
+    Ok(response)
+}
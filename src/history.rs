@@ -0,0 +1,449 @@
+use iron::prelude::*;
+use iron::status;
+use iron::mime::Mime;
+use urlencoded;
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use serde_json;
+
+use config::Station;
+use identity::DutIdentity;
+use logs::{self, LogSource};
+use stdf::StdfWriter;
+use super::{InterfaceState, LogMessage, ScenarioState, TestResult, TestTiming};
+
+/// When there is no history file, only this many runs are kept (in memory).
+const MEMORY_RUN_LIMIT: usize = 100;
+
+/// When there is no history file, only this many of each run's log messages
+/// are kept, the most recent ones.
+const MEMORY_RUN_LOG_LIMIT: usize = 10_000;
+
+/// Number of runs returned by /runs.json if no "limit" is given.
+const DEFAULT_PAGE_SIZE: usize = 50;
+
+/// The run that is currently in progress, or the one that most recently finished.
+#[derive(Clone, Debug, Serialize)]
+pub struct CurrentRun {
+    /// ID the run will be stored under.
+    pub id: u64,
+
+    /// Scenario that was STARTed.
+    pub scenario: String,
+
+    /// When START was received.
+    pub started: time::Duration,
+
+    /// When FINISH was received, or when the run was saved without one
+    /// because exclave went away or started another run.
+    pub finished: Option<time::Duration>,
+
+    /// Wall time between START and FINISH.
+    pub duration: Option<time::Duration>,
+
+    /// The result code from FINISH, if it has been received.
+    pub result: Option<u32>,
+
+    /// The unit under test, if one was identified before START.
+    pub identity: Option<DutIdentity>,
+}
+
+/// The outcome of a single test within a stored run.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunTest {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub result: TestResult,
+
+    #[serde(default)]
+    pub timing: TestTiming,
+}
+
+/// Everything about a stored run, apart from its logs.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub id: u64,
+    pub jig: String,
+    #[serde(default)]
+    pub jig_name: String,
+    pub scenario: String,
+    pub started: time::Duration,
+
+    /// None if the run never finished, e.g. because exclave went away.
+    pub finished: Option<time::Duration>,
+
+    /// Wall time between START and FINISH.
+    #[serde(default)]
+    pub duration: Option<time::Duration>,
+
+    /// The result code from FINISH, if there was one.
+    pub result: Option<u32>,
+    pub scenario_state: ScenarioState,
+
+    #[serde(default)]
+    pub identity: Option<DutIdentity>,
+
+    /// The station the run was tested on.
+    #[serde(default)]
+    pub station: Station,
+    pub tests: Vec<RunTest>,
+}
+
+/// A complete stored run, as written to the history file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub run: RunSummary,
+    pub logs: Vec<LogMessage>,
+}
+
+impl RunSummary {
+    /// Capture the state of `run` as it stands now.
+    pub fn from_state(data: &InterfaceState, run: &CurrentRun) -> RunSummary {
+        let no_tests = vec![];
+        let test_ids = data.tests.get(&run.scenario).unwrap_or(&no_tests);
+        let tests = test_ids.iter().map(|id| {
+            let id_lc = id.to_lowercase();
+            RunTest {
+                id: id.clone(),
+                name: data.test_names.get(&id_lc).cloned().unwrap_or_default(),
+                description: data.test_descriptions.get(&id_lc).cloned().unwrap_or_default(),
+                result: data.test_results.get(id).cloned().unwrap_or(TestResult::Pending),
+                timing: data.test_timings.get(id).cloned().unwrap_or_default(),
+            }
+        }).collect();
+
+        let scenario_state = match run.result {
+            None => ScenarioState::Running,
+            Some(_) => data.scenario_state.clone(),
+        };
+
+        RunSummary {
+            id: run.id,
+            jig: data.jig.clone(),
+            jig_name: data.jig_name.clone(),
+            scenario: run.scenario.clone(),
+            started: run.started,
+            finished: run.finished,
+            duration: run.duration,
+            result: run.result,
+            scenario_state,
+            identity: run.identity.clone(),
+            station: data.station.clone(),
+            tests,
+        }
+    }
+}
+
+/// Where the logs for a stored run can be found.
+enum StoredLogs {
+    /// Byte offset of the run's line in the history file.
+    File(u64),
+
+    /// The history is not being saved, so the logs are kept here.
+    Memory(Vec<LogMessage>),
+}
+
+/// Every run that has been recorded, oldest first.
+pub struct RunHistory {
+    path: Option<PathBuf>,
+    next_id: u64,
+    runs: Vec<(RunSummary, StoredLogs)>,
+
+    /// If set, each run is also written out as STDF when it is added.
+    stdf: Option<StdfWriter>,
+}
+
+impl RunHistory {
+    /// A history that only lasts as long as this process.
+    pub fn new() -> RunHistory {
+        RunHistory {
+            path: None,
+            next_id: 1,
+            runs: vec![],
+            stdf: None,
+        }
+    }
+
+    /// Load the history from `path`, which is created if it doesn't exist.
+    /// New runs are appended to it.
+    pub fn open(path: &Path) -> io::Result<RunHistory> {
+        let mut history = RunHistory {
+            path: Some(path.to_path_buf()),
+            next_id: 1,
+            runs: vec![],
+            stdf: None,
+        };
+
+        let mut reader = BufReader::new(OpenOptions::new().read(true).append(true).create(true).open(path)?);
+        let mut offset = 0;
+        loop {
+            let mut line = String::new();
+            let len = reader.read_line(&mut line)?;
+            if len == 0 {
+                break;
+            }
+
+            // A run that was only partly written when we last stopped.  Finish
+            // the line off so that the next run starts on a line of its own.
+            if !line.ends_with('\n') {
+                reader.get_mut().write_all(b"\n")?;
+            }
+
+            match serde_json::from_str::<RunRecord>(&line) {
+                Ok(record) => {
+                    if record.run.id >= history.next_id {
+                        history.next_id = record.run.id + 1;
+                    }
+                    history.runs.push((record.run, StoredLogs::File(offset)));
+                },
+                Err(e) => eprintln!("Skipping unreadable run at byte {} of {}: {}", offset, path.display(), e),
+            }
+            offset += len as u64;
+        }
+        Ok(history)
+    }
+
+    /// Reserve an ID for a new run.
+    pub fn allocate_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// Write each run that is added from now on out as STDF, too.
+    pub fn set_stdf(&mut self, writer: StdfWriter) {
+        self.stdf = Some(writer);
+    }
+
+    /// Store `run` along with its `logs`, which are read as they are written
+    /// out rather than all at once.
+    pub fn add(&mut self, run: RunSummary, logs: &dyn LogSource) {
+        if self.runs.iter().any(|(stored, _)| stored.id == run.id) {
+            eprintln!("Not saving run {} again, since it is already in the history", run.id);
+            return;
+        }
+
+        if let Some(ref stdf) = self.stdf {
+            if let Err(e) = stdf.write_run(&run) {
+                eprintln!("Unable to write run {} as STDF to {}: {}", run.id, stdf.dir().display(), e);
+            }
+        }
+
+        let stored = match self.path {
+            None => StoredLogs::Memory(recent_logs(logs)),
+            Some(ref path) => match RunHistory::append(path, &run, logs) {
+                Ok(offset) => StoredLogs::File(offset),
+                Err(e) => {
+                    eprintln!("Unable to save run {} to {}: {}", run.id, path.display(), e);
+                    StoredLogs::Memory(recent_logs(logs))
+                },
+            },
+        };
+        self.runs.push((run, stored));
+
+        if self.path.is_none() && self.runs.len() > MEMORY_RUN_LIMIT {
+            self.runs.remove(0);
+        }
+    }
+
+    /// Write `run` and `logs` out as a RunRecord, one message at a time,
+    /// since a long run's logs may not fit in memory all at once.
+    fn append(path: &Path, run: &RunSummary, logs: &dyn LogSource) -> io::Result<u64> {
+        let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+        let offset = file.seek(SeekFrom::End(0))?;
+        let mut out = BufWriter::new(file);
+        write!(out, "{{\"run\":{},\"logs\":[", serde_json::to_string(run).unwrap())?;
+
+        let mut result = Ok(());
+        let mut separator = "";
+        logs.scan(None, &mut |log| {
+            result = write!(out, "{}{}", separator, serde_json::to_string(log).unwrap());
+            separator = ",";
+            result.is_ok()
+        });
+        result?;
+
+        out.write_all(b"]}\n")?;
+        out.flush()?;
+        Ok(offset)
+    }
+
+    /// Look up a stored run, including its logs.
+    pub fn get(&self, id: u64) -> Option<RunRecord> {
+        let (summary, stored) = self.runs.iter().find(|(s, _)| s.id == id)?;
+        match stored {
+            StoredLogs::Memory(logs) => Some(RunRecord { run: summary.clone(), logs: logs.clone() }),
+            StoredLogs::File(offset) => match self.read_at(*offset) {
+                Ok(record) => Some(record),
+                Err(e) => { eprintln!("Unable to read run {} from history: {}", id, e); None },
+            },
+        }
+    }
+
+    fn read_at(&self, offset: u64) -> io::Result<RunRecord> {
+        let path = self.path.as_ref().expect("run stored in a file, but there is no history file");
+        let mut reader = BufReader::new(File::open(path)?);
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    /// The most recently stored run other than `current`, including its logs.
+    pub fn previous(&self, current: Option<u64>) -> Option<RunRecord> {
+        let id = self.runs.iter().rev().map(|(s, _)| s.id).find(|&id| Some(id) != current)?;
+        self.get(id)
+    }
+
+    /// Summaries of the runs that match `filter`, newest first.
+    pub fn query(&self, filter: &RunFilter) -> Vec<&RunSummary> {
+        self.runs.iter().rev().map(|(s, _)| s).filter(|s| filter.matches(s)).collect()
+    }
+}
+
+/// The last MEMORY_RUN_LOG_LIMIT messages in `logs`.
+fn recent_logs(logs: &dyn LogSource) -> Vec<LogMessage> {
+    let mut recent = VecDeque::new();
+    logs.scan(None, &mut |log| {
+        if recent.len() >= MEMORY_RUN_LOG_LIMIT {
+            recent.pop_front();
+        }
+        recent.push_back(log.clone());
+        true
+    });
+    recent.into()
+}
+
+/// Which runs /runs.json should return.
+pub struct RunFilter {
+    scenario: Option<String>,
+    serial: Option<String>,
+    result: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+impl RunFilter {
+    fn matches(&self, run: &RunSummary) -> bool {
+        if let Some(ref scenario) = self.scenario {
+            if &run.scenario != scenario {
+                return false;
+            }
+        }
+
+        if let Some(ref serial) = self.serial {
+            if run.identity.as_ref().map(|i| &i.serial) != Some(serial) {
+                return false;
+            }
+        }
+
+        if let Some(ref result) = self.result {
+            let matched = match result.as_str() {
+                "pass" => matches!(run.scenario_state, ScenarioState::Pass),
+                "fail" => matches!(run.scenario_state, ScenarioState::Fail),
+                "incomplete" => run.result.is_none(),
+                code => code.parse::<u32>().ok().is_some_and(|c| run.result == Some(c)),
+            };
+            if !matched {
+                return false;
+            }
+        }
+
+        if let Some(since) = self.since {
+            if run.started.as_secs() < since {
+                return false;
+            }
+        }
+
+        if let Some(until) = self.until {
+            if run.started.as_secs() > until {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[derive(Serialize)]
+struct RunPage<'a> {
+    /// Number of runs matching the filter, across all pages.
+    total: usize,
+    offset: usize,
+    limit: usize,
+    runs: Vec<&'a RunSummary>,
+}
+
+pub fn parse_number<T: ::std::str::FromStr>(query: &HashMap<String, Vec<String>>, key: &str) -> Result<Option<T>, Response> {
+    match query.get(key) {
+        None => Ok(None),
+        Some(s) => match s[0].parse() {
+            Ok(o) => Ok(Some(o)),
+            Err(_) => Err(Response::with((status::BadRequest, format!("Unable to parse {} value: {:?}", key, s)))),
+        },
+    }
+}
+
+pub fn show_runs_json(request: &mut Request, history: &Arc<Mutex<RunHistory>>) -> IronResult<Response> {
+    let content_type = "application/json".parse::<Mime>().unwrap();
+    let query = match request.get_ref::<urlencoded::UrlEncodedQuery>() {
+        Ok(hashmap) => hashmap.clone(),
+        Err(_) => HashMap::new(),
+    };
+
+    let filter = RunFilter {
+        scenario: query.get("scenario").map(|s| s[0].clone()),
+        serial: query.get("serial").map(|s| s[0].clone()),
+        result: query.get("result").map(|s| s[0].to_lowercase()),
+        since: match parse_number(&query, "since") { Ok(v) => v, Err(r) => return Ok(r) },
+        until: match parse_number(&query, "until") { Ok(v) => v, Err(r) => return Ok(r) },
+    };
+    let offset = match parse_number(&query, "offset") { Ok(v) => v.unwrap_or(0), Err(r) => return Ok(r) };
+    let limit = match parse_number(&query, "limit") { Ok(v) => v.unwrap_or(DEFAULT_PAGE_SIZE), Err(r) => return Ok(r) };
+
+    let history = history.lock().unwrap();
+    let runs = history.query(&filter);
+    let page = RunPage {
+        total: runs.len(),
+        offset,
+        limit,
+        runs: runs.into_iter().skip(offset).take(limit).collect(),
+    };
+
+    Ok(Response::with((content_type, status::Ok, serde_json::to_string(&page).unwrap())))
+}
+
+/// Serve /runs/<id>.json, or the run's logs from /runs/<id>/log.json
+pub fn show_run_json(request: &mut Request, history: &Arc<Mutex<RunHistory>>) -> IronResult<Response> {
+    let content_type = "application/json".parse::<Mime>().unwrap();
+    let name = request.url.path().join("/");
+    let stem = match name.strip_suffix(".json") {
+        Some(stem) => stem,
+        None => return Ok(Response::with((status::NotFound, format!("No such run: {}", name)))),
+    };
+    let (id, logs_only) = match stem.split_once('/') {
+        None => (stem.parse(), false),
+        Some((id, "log")) => (id.parse(), true),
+        _ => return Ok(Response::with((status::NotFound, format!("No such run: {}", name)))),
+    };
+    let id: u64 = match id {
+        Ok(id) => id,
+        Err(_) => return Ok(Response::with((status::NotFound, format!("No such run: {}", name)))),
+    };
+
+    let record = match history.lock().unwrap().get(id) {
+        Some(record) => record,
+        None => return Ok(Response::with((status::NotFound, format!("No such run: {}", id)))),
+    };
+    if logs_only {
+        return logs::show_logs(request, &record.logs);
+    }
+    Ok(Response::with((content_type, status::Ok, serde_json::to_string(&record).unwrap())))
+}
@@ -0,0 +1,150 @@
+use iron::prelude::*;
+use iron::status;
+use iron::mime::Mime;
+use regex::Regex;
+use urlencoded;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde_json;
+
+use audit;
+use events::{EventBus, StateEvent};
+use super::InterfaceState;
+
+/// Which physical unit is under test, as entered before START.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DutIdentity {
+    pub serial: String,
+
+    #[serde(default)]
+    pub lot: Option<String>,
+
+    #[serde(default)]
+    pub work_order: Option<String>,
+
+    #[serde(default)]
+    pub operator: Option<String>,
+}
+
+impl DutIdentity {
+    /// The optional fields that were filled in, along with their names.
+    pub fn extra_fields(&self) -> Vec<(&'static str, &str)> {
+        let mut fields = vec![];
+        if let Some(ref lot) = self.lot {
+            fields.push(("lot", lot.as_str()));
+        }
+        if let Some(ref work_order) = self.work_order {
+            fields.push(("work_order", work_order.as_str()));
+        }
+        if let Some(ref operator) = self.operator {
+            fields.push(("operator", operator.as_str()));
+        }
+        fields
+    }
+
+    /// A one-line summary, as sent to exclave in a LOG line.
+    pub fn describe(&self) -> String {
+        let mut summary = format!("DUT serial={}", self.serial);
+        for (name, value) in self.extra_fields() {
+            summary.push_str(&format!(" {}={}", name, value));
+        }
+        summary
+    }
+}
+
+/// An identity as entered, before it has been checked.
+#[derive(Clone, Debug, Default)]
+pub struct IdentityFields {
+    pub serial: Option<String>,
+    pub lot: Option<String>,
+    pub work_order: Option<String>,
+    pub operator: Option<String>,
+}
+
+/// Trim a field, treating a blank one as missing.
+fn tidy(field: Option<String>) -> Option<String> {
+    field.map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+/// Compile --serial-pattern so that it must match the whole serial number.
+pub fn serial_pattern(pattern: &str) -> Result<Regex, String> {
+    Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| e.to_string())
+}
+
+fn identity_response(identity: &Option<DutIdentity>) -> Response {
+    let content_type = "application/json".parse::<Mime>().unwrap();
+    Response::with((content_type, status::Ok, serde_json::to_string(identity).unwrap()))
+}
+
+/// Check `fields` and make them the identity of the next unit to be tested.
+/// Returns why not if they aren't acceptable.
+pub fn update_identity(fields: IdentityFields,
+                       state: &Arc<Mutex<InterfaceState>>,
+                       events: &Arc<Mutex<EventBus>>,
+                       pattern: &Option<Regex>) -> Result<DutIdentity, String> {
+    let serial = match tidy(fields.serial) {
+        Some(s) => s,
+        None => return Err("A serial number is required".to_string()),
+    };
+    if let Some(ref pattern) = *pattern {
+        if !pattern.is_match(&serial) {
+            return Err(format!("Serial number {:?} does not match the pattern {}", serial, pattern));
+        }
+    }
+
+    let identity = DutIdentity {
+        serial,
+        lot: tidy(fields.lot),
+        work_order: tidy(fields.work_order),
+        operator: tidy(fields.operator),
+    };
+
+    let mut data = state.lock().unwrap();
+    data.identity = Some(identity.clone());
+    events.lock().unwrap().publish(StateEvent::Identity { identity: Some(identity.clone()) });
+    Ok(identity)
+}
+
+/// Forget the identity entered for the next unit.
+pub fn forget_identity(state: &Arc<Mutex<InterfaceState>>, events: &Arc<Mutex<EventBus>>) {
+    let mut data = state.lock().unwrap();
+    data.identity = None;
+    events.lock().unwrap().publish(StateEvent::Identity { identity: None });
+}
+
+/// Serve /identity, which sets the identity of the next unit to be tested
+/// from "serial" and the optional "lot", "work_order", and "operator".
+pub fn set_identity(request: &mut Request,
+                    state: &Arc<Mutex<InterfaceState>>,
+                    events: &Arc<Mutex<EventBus>>,
+                    pattern: &Option<Regex>) -> IronResult<Response> {
+    let query = match request.get_ref::<urlencoded::UrlEncodedQuery>() {
+        Ok(hashmap) => hashmap.clone(),
+        Err(_) => HashMap::new(),
+    };
+    let field = |name: &str| query.get(name).map(|v| v[0].clone());
+    let fields = IdentityFields {
+        serial: field("serial"),
+        lot: field("lot"),
+        work_order: field("work_order"),
+        operator: field("operator"),
+    };
+    let parameters: Vec<(&str, String)> = ["serial", "lot", "work_order", "operator"].iter().filter_map(|&name| field(name).map(|v| (name, v))).collect();
+
+    let response = match update_identity(fields, state, events, pattern) {
+        Ok(identity) => Ok(identity_response(&Some(identity))),
+        Err(e) => Ok(Response::with((status::BadRequest, e))),
+    };
+    audit::record_response(request, "identity", &parameters, &response);
+    response
+}
+
+/// Serve /identity/clear, which forgets the identity entered for the next unit.
+pub fn clear_identity(request: &mut Request, state: &Arc<Mutex<InterfaceState>>, events: &Arc<Mutex<EventBus>>) -> IronResult<Response> {
+    forget_identity(state, events);
+    let response = Ok(identity_response(&None));
+    audit::record_response(request, "clear_identity", &[], &response);
+    response
+}
@@ -0,0 +1,192 @@
+use iron::prelude::*;
+use iron::status;
+use iron::mime::Mime;
+use regex::Regex;
+use urlencoded;
+
+use std::collections::HashMap;
+
+use serde_json;
+
+use history::parse_number;
+use super::LogMessage;
+
+/// Most log messages returned by one request when paging with "after".
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Most log messages looked through by one request when paging, so that a
+/// search that matches little can't read every spilled message at once.
+const MAX_PAGE_SCAN: usize = 100_000;
+
+/// A page of logs, along with where the next page starts.
+#[derive(Serialize)]
+struct LogPage {
+    logs: Vec<LogMessage>,
+
+    /// Pass this as "after" to get the messages that follow this page.
+    next_cursor: u64,
+
+    /// Whether there may be more messages after `next_cursor`, even if
+    /// this page has none.
+    more: bool,
+}
+
+/// Which log messages to return.  Every condition that is given must match.
+#[derive(Default)]
+struct LogFilter {
+    /// The message must have one of these classes, if any are given.  The
+    /// same goes for `unit_ids` and `unit_types`.
+    message_classes: Vec<String>,
+    unit_ids: Vec<String>,
+    unit_types: Vec<String>,
+
+    /// Unix times, in seconds.
+    since: Option<f64>,
+    until: Option<f64>,
+
+    /// Lowercase text that must appear somewhere in the message.
+    contains: Option<String>,
+    regex: Option<Regex>,
+}
+
+impl LogFilter {
+    fn from_query(query: &HashMap<String, Vec<String>>) -> Result<LogFilter, Response> {
+        let regex = match query.get("regex") {
+            None => None,
+            Some(r) => match Regex::new(&r[0]) {
+                Ok(r) => Some(r),
+                Err(e) => return Err(Response::with((status::BadRequest, format!("Invalid regex: {}", e)))),
+            },
+        };
+
+        Ok(LogFilter {
+            message_classes: query.get("message_class").cloned().unwrap_or_default(),
+            unit_ids: query.get("unit_id").cloned().unwrap_or_default(),
+            unit_types: query.get("unit_type").cloned().unwrap_or_default(),
+            since: parse_number(query, "since")?,
+            until: parse_number(query, "until")?,
+            contains: query.get("contains").map(|c| c[0].to_lowercase()),
+            regex,
+        })
+    }
+
+    fn matches(&self, log: &LogMessage) -> bool {
+        let one_of = |allowed: &Vec<String>, value: &String| allowed.is_empty() || allowed.contains(value);
+        let time = log.timestamp.as_secs_f64();
+
+        one_of(&self.message_classes, &log.message_class)
+            && one_of(&self.unit_ids, &log.unit_id)
+            && one_of(&self.unit_types, &log.unit_type)
+            && self.since.is_none_or(|since| time >= since)
+            && self.until.is_none_or(|until| time <= until)
+            && self.contains.as_ref().is_none_or(|c| log.message.to_lowercase().contains(c))
+            && self.regex.as_ref().is_none_or(|r| r.is_match(&log.message))
+    }
+}
+
+/// Somewhere log messages can be read from, in sequence order.
+pub trait LogSource {
+    /// Call `f` with each message whose sequence number is above `after`, or
+    /// with every message if `after` is None, until `f` returns false.
+    fn scan(&self, after: Option<u64>, f: &mut dyn FnMut(&LogMessage) -> bool);
+
+    /// If older messages have been moved out of memory, the sequence number
+    /// that every message held in memory comes after.
+    fn in_memory_after(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl LogSource for Vec<LogMessage> {
+    fn scan(&self, after: Option<u64>, f: &mut dyn FnMut(&LogMessage) -> bool) {
+        // The messages are in sequence order, so the start can be found by bisection.
+        let first = match after {
+            Some(after) => self.partition_point(|log| log.seq <= after),
+            None => 0,
+        };
+        for log in &self[first..] {
+            if !f(log) {
+                return;
+            }
+        }
+    }
+}
+
+/// Matching messages after sequence number `after`, up to `limit` of them,
+/// from among the next MAX_PAGE_SCAN messages.
+fn page_after(logs: &dyn LogSource, filter: &LogFilter, after: u64, limit: usize) -> LogPage {
+    let mut page = LogPage {
+        logs: vec![],
+        next_cursor: after,
+        more: false,
+    };
+    let mut scanned = 0;
+    logs.scan(Some(after), &mut |log| {
+        if page.logs.len() >= limit || scanned >= MAX_PAGE_SCAN {
+            page.more = true;
+            return false;
+        }
+        scanned += 1;
+        // Messages that don't match still move the cursor along, so that
+        // they aren't searched through again next time.
+        page.next_cursor = log.seq;
+        if filter.matches(log) {
+            page.logs.push(log.clone());
+        }
+        true
+    });
+    page
+}
+
+/// Matching messages from index `start` up to, but not including, index
+/// `end`, counting from the oldest message held in memory.
+fn slice(logs: &dyn LogSource, filter: &LogFilter, start: Option<usize>, end: Option<usize>) -> Vec<LogMessage> {
+    let start = start.unwrap_or(0);
+    let mut index = 0;
+    let mut found = vec![];
+    logs.scan(logs.in_memory_after(), &mut |log| {
+        if end.is_some_and(|end| index >= end) {
+            return false;
+        }
+        if index >= start && filter.matches(log) {
+            found.push(log.clone());
+        }
+        index += 1;
+        true
+    });
+    found
+}
+
+/// Serve a list of logs, such as /log.json or /log/current.json.
+///
+/// The messages can be narrowed down by "message_class", "unit_id",
+/// "unit_type", "since", "until", "contains", and "regex".
+///
+/// With "after" or "limit", returns a page of messages along with a
+/// "next_cursor" to pass as "after" next time.  Because this follows each
+/// message's sequence number rather than its position, it is unaffected by
+/// /truncate or by START moving messages between lists.  This is the only
+/// way to read messages that have been spilled to disk.
+///
+/// Otherwise, returns a plain array of the messages held in memory from
+/// index "start" to "end", as it always has.
+pub fn show_logs(request: &mut Request, logs: &dyn LogSource) -> IronResult<Response> {
+    let content_type = "application/json".parse::<Mime>().unwrap();
+    let query = match request.get_ref::<urlencoded::UrlEncodedQuery>() {
+        Ok(hashmap) => hashmap.clone(),
+        Err(_) => HashMap::new(),
+    };
+
+    let filter = match LogFilter::from_query(&query) { Ok(f) => f, Err(r) => return Ok(r) };
+    let after = match parse_number(&query, "after") { Ok(v) => v, Err(r) => return Ok(r) };
+    let limit = match parse_number(&query, "limit") { Ok(v) => v, Err(r) => return Ok(r) };
+    if after.is_some() || limit.is_some() {
+        let limit = limit.unwrap_or(MAX_PAGE_SIZE).min(MAX_PAGE_SIZE);
+        let page = page_after(logs, &filter, after.unwrap_or(0), limit);
+        return Ok(Response::with((content_type, status::Ok, serde_json::to_string(&page).unwrap())));
+    }
+
+    let start = match parse_number(&query, "start") { Ok(v) => v, Err(r) => return Ok(r) };
+    let end = match parse_number(&query, "end") { Ok(v) => v, Err(r) => return Ok(r) };
+    Ok(Response::with((content_type, status::Ok, serde_json::to_string(&slice(logs, &filter, start, end)).unwrap())))
+}
@@ -0,0 +1,356 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde_json;
+
+use logs::LogSource;
+use super::LogMessage;
+
+/// Spilled logs are split into files of about this size, so that old ones
+/// can be deleted once nothing needs them.
+const SEGMENT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Prefix and suffix of the names of spilled segment files.
+const SEGMENT_PREFIX: &str = "logs-";
+const SEGMENT_SUFFIX: &str = ".jsonl";
+
+/// Most in-memory messages copied out at a time while scanning, so that the
+/// store isn't kept locked for long.
+const SCAN_CHUNK: usize = 1000;
+
+/// Which of the log endpoints' lists of messages to look at.
+#[derive(Clone, Copy, Debug)]
+pub enum LogView {
+    /// Everything since the last /truncate.
+    All,
+
+    /// Everything since the last START.
+    CurrentRun,
+
+    /// Everything from the START before last up to the last START.
+    PreviousRun,
+}
+
+/// One file of spilled messages.
+#[derive(Clone)]
+struct Segment {
+    path: PathBuf,
+    first_seq: u64,
+    last_seq: u64,
+    count: usize,
+    bytes: u64,
+}
+
+/// Where messages go once they no longer fit in memory.
+struct Spill {
+    dir: PathBuf,
+
+    /// Oldest first.  The last one is being appended to.
+    segments: Vec<Segment>,
+    file: Option<File>,
+}
+
+impl Spill {
+    fn write(&mut self, log: &LogMessage) -> io::Result<()> {
+        let mut line = serde_json::to_string(log).unwrap();
+        line.push('\n');
+
+        let full = match self.segments.last() {
+            Some(segment) => segment.bytes >= SEGMENT_BYTES,
+            None => true,
+        };
+        if full || self.file.is_none() {
+            let path = self.dir.join(format!("{}{}{}", SEGMENT_PREFIX, log.seq, SEGMENT_SUFFIX));
+            self.file = Some(OpenOptions::new().create(true).append(true).open(&path)?);
+            self.segments.push(Segment {
+                path,
+                first_seq: log.seq,
+                last_seq: log.seq,
+                count: 0,
+                bytes: 0,
+            });
+        }
+
+        self.file.as_mut().unwrap().write_all(line.as_bytes())?;
+        let segment = self.segments.last_mut().unwrap();
+        segment.last_seq = log.seq;
+        segment.count += 1;
+        segment.bytes += line.len() as u64;
+        Ok(())
+    }
+
+    /// Delete every segment that only holds messages from before `seq`.
+    fn discard_before(&mut self, seq: u64) {
+        while self.segments.first().is_some_and(|s| s.last_seq < seq) {
+            let segment = self.segments.remove(0);
+            if self.segments.is_empty() {
+                self.file = None;
+            }
+            if let Err(e) = fs::remove_file(&segment.path) {
+                eprintln!("Unable to remove old log segment {}: {}", segment.path.display(), e);
+            }
+        }
+    }
+
+    /// The segments that hold messages from `lo` up to `hi`.
+    fn segments_between(&self, lo: u64, hi: u64) -> Vec<Segment> {
+        self.segments.iter().filter(|s| s.last_seq >= lo && s.first_seq < hi).cloned().collect()
+    }
+}
+
+/// Call `f` on each message in `segments` from `lo` up to `hi`, until it
+/// returns false.  Returns false if `f` did.
+fn scan_segments(segments: &[Segment], lo: u64, hi: u64, f: &mut dyn FnMut(&LogMessage) -> bool) -> bool {
+    for segment in segments {
+        let file = match File::open(&segment.path) {
+            Ok(f) => f,
+            Err(e) => { eprintln!("Unable to read log segment {}: {}", segment.path.display(), e); continue; },
+        };
+        for line in BufReader::new(file).lines() {
+            let log: LogMessage = match line.map(|l| serde_json::from_str(&l)) {
+                Ok(Ok(log)) => log,
+                _ => continue,
+            };
+            if log.seq < lo {
+                continue;
+            }
+            if log.seq >= hi {
+                return true;
+            }
+            if !f(&log) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Roughly how much memory a message takes up.
+fn message_bytes(log: &LogMessage) -> usize {
+    mem::size_of::<LogMessage>() + log.message_class.len() + log.unit_id.len() + log.unit_type.len() + log.message.len()
+}
+
+/// Every log message received, with the most recent ones kept in memory up
+/// to a limit.  Older ones are spilled to disk if a directory was given, or
+/// dropped if not.
+pub struct LogStore {
+    /// Sequence number for the next message.  Numbers start at 1.
+    next_seq: u64,
+
+    memory: VecDeque<LogMessage>,
+    memory_bytes: usize,
+    max_count: usize,
+    max_bytes: usize,
+
+    spill: Option<Spill>,
+
+    /// Messages before this were removed from LogView::All by /truncate.
+    truncated_before: u64,
+
+    /// First message of the current and previous runs.
+    current_start: u64,
+    previous_start: u64,
+}
+
+impl LogStore {
+    /// Keep at most `max_count` messages, or `max_bytes` of them, in memory.
+    pub fn new(max_count: usize, max_bytes: usize) -> LogStore {
+        LogStore {
+            next_seq: 1,
+            memory: VecDeque::new(),
+            memory_bytes: 0,
+            max_count,
+            max_bytes,
+            spill: None,
+            truncated_before: 1,
+            current_start: 1,
+            previous_start: 1,
+        }
+    }
+
+    /// Write messages that don't fit in memory to files in `dir`.  Any
+    /// segments left there by a previous run of this program are removed,
+    /// since their sequence numbers would clash with ours.
+    pub fn spill_to(&mut self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let stale = path.file_name()
+                            .and_then(|n| n.to_str())
+                            .is_some_and(|n| n.starts_with(SEGMENT_PREFIX) && n.ends_with(SEGMENT_SUFFIX));
+            if stale {
+                fs::remove_file(&path)?;
+            }
+        }
+        self.spill = Some(Spill {
+            dir: dir.to_path_buf(),
+            segments: vec![],
+            file: None,
+        });
+        Ok(())
+    }
+
+    /// Oldest message that some view still needs.
+    fn needed_from(&self) -> u64 {
+        self.truncated_before.min(self.previous_start)
+    }
+
+    /// Add a message, giving it the next sequence number.
+    pub fn push(&mut self, mut log: LogMessage) -> LogMessage {
+        log.seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.memory_bytes += message_bytes(&log);
+        self.memory.push_back(log.clone());
+
+        while self.memory.len() > self.max_count || self.memory_bytes > self.max_bytes {
+            let old = match self.memory.pop_front() {
+                Some(old) => old,
+                None => break,
+            };
+            self.memory_bytes -= message_bytes(&old);
+            if old.seq < self.needed_from() {
+                continue;
+            }
+            if let Some(ref mut spill) = self.spill {
+                if let Err(e) = spill.write(&old) {
+                    eprintln!("Unable to spill log message {} to {}: {}", old.seq, spill.dir.display(), e);
+                }
+            }
+        }
+        log
+    }
+
+    /// Get rid of everything that no view can show any more.
+    fn discard_unneeded(&mut self) {
+        let needed_from = self.needed_from();
+        while self.memory.front().is_some_and(|log| log.seq < needed_from) {
+            let old = self.memory.pop_front().unwrap();
+            self.memory_bytes -= message_bytes(&old);
+        }
+        if let Some(ref mut spill) = self.spill {
+            spill.discard_before(needed_from);
+        }
+    }
+
+    /// A new run started: the current run's messages become the previous run's.
+    pub fn start_run(&mut self) {
+        self.previous_start = self.current_start;
+        self.current_start = self.next_seq;
+        self.discard_unneeded();
+    }
+
+    /// Empty LogView::All.
+    pub fn truncate(&mut self) {
+        self.truncated_before = self.next_seq;
+        self.discard_unneeded();
+    }
+
+    /// One of the lists of messages in `store`.  Reading it only locks the
+    /// store briefly at a time, and never while reading spilled messages.
+    pub fn view(store: &Mutex<LogStore>, view: LogView) -> StoreView<'_> {
+        StoreView { store, view }
+    }
+
+    /// The sequence numbers of the messages in `view`, from the first up to,
+    /// but not including, the last.
+    fn bounds(&self, view: LogView) -> (u64, u64) {
+        match view {
+            LogView::All => (self.truncated_before, self.next_seq),
+            LogView::CurrentRun => (self.current_start, self.next_seq),
+            LogView::PreviousRun => (self.previous_start, self.current_start),
+        }
+    }
+
+    /// Sequence number of the oldest message held in memory.
+    fn in_memory_from(&self) -> u64 {
+        self.memory.front().map(|log| log.seq).unwrap_or(self.next_seq)
+    }
+
+    /// Number of messages held in memory, and roughly how many bytes they take.
+    pub fn memory_usage(&self) -> (usize, usize) {
+        (self.memory.len(), self.memory_bytes)
+    }
+
+    /// Number of messages spilled to disk, and the size of the files holding them.
+    pub fn spill_usage(&self) -> (usize, u64) {
+        match self.spill {
+            None => (0, 0),
+            Some(ref spill) => spill.segments.iter().fold((0, 0), |(count, bytes), s| (count + s.count, bytes + s.bytes)),
+        }
+    }
+}
+
+/// One of the log endpoints' lists of messages.
+pub struct StoreView<'a> {
+    store: &'a Mutex<LogStore>,
+    view: LogView,
+}
+
+impl<'a> LogSource for StoreView<'a> {
+    /// Scans the messages that were in the view when the scan started.  The
+    /// store is only locked while working out where the next messages are,
+    /// so new messages can keep arriving in the meantime.
+    fn scan(&self, after: Option<u64>, f: &mut dyn FnMut(&LogMessage) -> bool) {
+        let (mut next, hi) = {
+            let store = self.store.lock().unwrap();
+            let (lo, hi) = store.bounds(self.view);
+            match after {
+                Some(after) => (lo.max(after.saturating_add(1)), hi),
+                None => (lo, hi),
+            }
+        };
+
+        while next < hi {
+            let mut segments = vec![];
+            let mut chunk = vec![];
+            let spilled_to = {
+                let store = self.store.lock().unwrap();
+                let in_memory_from = store.in_memory_from();
+                if next < in_memory_from {
+                    // Anything older than what's in memory is on disk, if anywhere.
+                    let spilled_to = in_memory_from.min(hi);
+                    if let Some(ref spill) = store.spill {
+                        segments = spill.segments_between(next, spilled_to);
+                    }
+                    Some(spilled_to)
+                } else {
+                    let first = store.memory.partition_point(|log| log.seq < next);
+                    chunk.extend(store.memory.range(first..).take_while(|log| log.seq < hi).take(SCAN_CHUNK).cloned());
+                    None
+                }
+            };
+
+            match spilled_to {
+                Some(spilled_to) => {
+                    if !scan_segments(&segments, next, spilled_to, f) {
+                        return;
+                    }
+                    next = spilled_to;
+                },
+                None => {
+                    for log in &chunk {
+                        if !f(log) {
+                            return;
+                        }
+                    }
+                    match chunk.last() {
+                        Some(log) => next = log.seq + 1,
+                        None => return,
+                    }
+                },
+            }
+        }
+    }
+
+    fn in_memory_after(&self) -> Option<u64> {
+        let store = self.store.lock().unwrap();
+        let (lo, _) = store.bounds(self.view);
+        let in_memory_from = store.in_memory_from();
+        if in_memory_from > lo { Some(in_memory_from - 1) } else { None }
+    }
+}
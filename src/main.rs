@@ -2,26 +2,68 @@
 extern crate serde_derive;
 extern crate serde_json;
 extern crate iron;
+extern crate hyper;
 extern crate staticfile;
 extern crate urlencoded;
 extern crate mount;
+#[macro_use]
 extern crate clap;
+extern crate regex;
+extern crate websocket;
+extern crate pbkdf2;
+extern crate sha2;
+extern crate rustls;
+extern crate signal_hook;
+extern crate toml;
+
+mod api;
+mod audit;
+mod auth;
+mod bootstrap;
+mod cfti;
+mod config;
+mod events;
+mod history;
+mod identity;
+mod logs;
+mod logstore;
+mod metrics;
+mod replay;
+mod report;
+mod simulator;
+mod stdf;
+mod supervisor;
+mod timeline;
+mod tls;
+mod trace;
+mod transport;
+mod ws;
 
 use clap::{Arg, App};
 
 use iron::prelude::*;
 use iron::status;
 use iron::mime::Mime;
-use mount::Mount;
 use staticfile::Static;
 
-use std::io::{self, Write};
+use std::io::{self, BufRead};
 use std::sync::{Arc, Mutex};
 use std::{thread, time};
 use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use cfti::{DescribeClass, DescribeField, IncomingMessage, ProtocolError, ProtocolErrors};
+use events::{EventBus, StateEvent};
+use history::{CurrentRun, RunHistory, RunSummary};
+use identity::DutIdentity;
+use logstore::{LogStore, LogView};
 
 const SERVER_SIGNATURE: &'static str = "CFTI HTTP 1.0";
 
+/// Longest a control request may block with "wait", in milliseconds.
+const MAX_WAIT_MS: u64 = 60_000;
+
 #[derive(Clone, Debug)]
 enum OutgoingMessage {
     Hello(String),
@@ -37,8 +79,11 @@ enum OutgoingMessage {
 }
 
 // <message-type>   <unit>    <unit-type>    <unix-time-secs>    <unix-time-nsecs>    <message>
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct LogMessage {
+    /// Increases by one for every message, and is never reused.
+    #[serde(default)]
+    seq: u64,
     message_class: String,
     unit_id: String,
     unit_type: String,
@@ -46,7 +91,7 @@ struct LogMessage {
     message: String,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 enum ScenarioState {
     /// The scenario has not yet been run
     Pending,
@@ -62,6 +107,30 @@ enum ScenarioState {
 }
 
 #[derive(Clone, Debug, Serialize)]
+enum ConnectionStatus {
+    /// Lines are being received from the server.
+    Connected {
+        since: time::Duration,
+    },
+
+    /// The server went away, either by closing its end of the connection or by a read error.
+    Disconnected {
+        since: time::Duration,
+        reason: String,
+    },
+}
+
+/// What to do once the server has gone away.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DisconnectPolicy {
+    /// Quit, as though EXIT had been received.
+    Exit,
+
+    /// Keep serving the last-known state and logs.
+    Stay,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 enum TestResult {
     /// The test has not yet been run.
     Pending,
@@ -79,12 +148,35 @@ enum TestResult {
     Skipped(String /*reason*/),
 }
 
+/// When a test ran, as seen by the arrival of its RUNNING and PASS/FAIL/SKIP lines.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct TestTiming {
+    /// When RUNNING was received, since the Unix epoch.
+    started: Option<time::Duration>,
+
+    /// When PASS, FAIL, or SKIP was received, since the Unix epoch.
+    finished: Option<time::Duration>,
+
+    /// How long the test took, if it was seen both starting and finishing.
+    duration: Option<time::Duration>,
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub struct InterfaceState {
 
     /// The identifier of the server (returned on the HELLO line).
     server: String,
 
+    /// Which test station this is, from the config file or command line
+    station: config::Station,
+
+    /// Whether the server is still talking to us
+    connection: ConnectionStatus,
+
+    /// The exclave process we started, with --exclave
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exclave: Option<supervisor::ChildStatus>,
+
     /// Current jig identifier (returned by JIG)
     jig: String,
 
@@ -106,9 +198,15 @@ pub struct InterfaceState {
     /// ID of the currently-selected scenario
     scenario: String,
 
+    /// The unit that will be tested by the next START
+    identity: Option<DutIdentity>,
+
     /// What state the current scenario is in
     scenario_state: ScenarioState,
 
+    /// The run in progress, or the most recent one if none is in progress
+    run: Option<CurrentRun>,
+
     /// List of tests in each scenario, returned by TESTS [x]
     tests: HashMap<String, Vec<String>>,
 
@@ -121,17 +219,33 @@ pub struct InterfaceState {
     /// Map of test results, usually will default to "Pending".
     test_results: HashMap<String, TestResult>,
 
-    /// Incoming messages (for debugging)
+    /// Map of test start and end times for the current run
+    test_timings: HashMap<String, TestTiming>,
+
+    /// Incoming messages (for debugging).  Only served by /stdin.txt, which
+    /// needs a higher role than /current.json.
+    #[serde(skip_serializing)]
     stdin_log: Vec<String>,
 
     /// Decide whether to log messages to stdin
     log_stdin: bool,
 
-    /// Logs from the current run (i.e. since "START" was received)
-    current_log: Vec<LogMessage>,
+    /// Lines from the server that could not be parsed (served by /protocol/errors.json)
+    #[serde(skip_serializing)]
+    protocol_errors: ProtocolErrors,
+
+    /// When the most recent line was received from the server
+    #[serde(skip_serializing)]
+    last_line: Option<time::Duration>,
 
-    /// Logs from the previous run (i.e. since "START" was received, until "STOP" was received)
-    previous_log: Vec<LogMessage>,
+    /// FINISH result codes that count as a pass
+    #[serde(skip_serializing)]
+    pass_codes: RangeInclusive<u32>,
+}
+
+/// The current time, as a duration since the Unix epoch.
+fn unix_time() -> time::Duration {
+    time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap_or_default()
 }
 
 fn cfti_escape(msg: String) -> String {
@@ -155,21 +269,29 @@ fn cfti_unescape(msg: String) -> String {
 }
 
 fn cfti_send(msg: OutgoingMessage) {
-    let tx = io::stdout();
-    let result = match msg {
-        OutgoingMessage::Hello(s) => writeln!(tx.lock(), "HELLO {}", cfti_escape(s)),
-        OutgoingMessage::GetJig => writeln!(tx.lock(), "JIG"),
-        OutgoingMessage::Scenarios => writeln!(tx.lock(), "SCENARIOS"),
-        OutgoingMessage::Scenario(s) => writeln!(tx.lock(), "SCENARIO {}", cfti_escape(s)),
-        OutgoingMessage::GetTests => writeln!(tx.lock(), "TESTS"),
-        OutgoingMessage::StartTests(s) => writeln!(tx.lock(), "START {}", cfti_escape(s)),
-        OutgoingMessage::AbortTests => writeln!(tx.lock(), "ABORT"),
-        OutgoingMessage::Log(s) => writeln!(tx.lock(), "LOG {}", cfti_escape(s)),
-        OutgoingMessage::Pong(s) => writeln!(tx.lock(), "PONG {}", cfti_escape(s)),
-        OutgoingMessage::Shutdown(s) => writeln!(tx.lock(), "SHUTDOWN {}", cfti_escape(s)),
+    let line = match msg.clone() {
+        OutgoingMessage::Hello(s) => format!("HELLO {}", cfti_escape(s)),
+        OutgoingMessage::GetJig => "JIG".to_string(),
+        OutgoingMessage::Scenarios => "SCENARIOS".to_string(),
+        OutgoingMessage::Scenario(s) => format!("SCENARIO {}", cfti_escape(s)),
+        OutgoingMessage::GetTests => "TESTS".to_string(),
+        OutgoingMessage::StartTests(s) => format!("START {}", cfti_escape(s)),
+        OutgoingMessage::AbortTests => "ABORT".to_string(),
+        OutgoingMessage::Log(s) => format!("LOG {}", cfti_escape(s)),
+        OutgoingMessage::Pong(s) => format!("PONG {}", cfti_escape(s)),
+        OutgoingMessage::Shutdown(s) => format!("SHUTDOWN {}", cfti_escape(s)),
+    };
+    // The simulator stands in for the server, if it's running.
+    let result = if simulator::deliver(&msg) {
+        Ok(())
+    } else {
+        transport::write_line(&line)
     };
-    if result.is_err() {
-        println!("Unable to write outgoing message: {}", result.unwrap_err());
+    trace::record(trace::Direction::Outgoing, &line, || format!("{:?}", msg));
+
+    // This can't go to the server, since that's where the failed write was headed.
+    if let Err(e) = result {
+        eprintln!("Unable to write outgoing message: {}", e);
     }
 }
 
@@ -187,136 +309,108 @@ fn show_stdin(_: &mut Request, state: &Arc<Mutex<InterfaceState>>) -> IronResult
     Ok(Response::with((content_type, status::Ok, state.stdin_log.join("\n"))))
 }
 
-fn show_logs_json(request: &mut Request, logs: &Arc<Mutex<Vec<LogMessage>>>) -> IronResult<Response> {
-    let content_type = "application/json".parse::<Mime>().unwrap();
-    let query = match request.get_ref::<urlencoded::UrlEncodedQuery>() {
-        Ok(hashmap) => hashmap.clone(),
-        Err(_) => HashMap::new(),
-    };
-
-    let ref logs = *logs.lock().unwrap();
-
-    let start = match query.get("start") {
-        Some(s) => match s[0].parse() {
-            Ok(o) => match o {
-                o if o >= logs.len() => return Ok(Response::with((content_type, status::Ok, "[]".to_string()))),
-                o => o,
-            },
-            Err(e) => return Ok(Response::with((status::BadRequest, format!("Unable to parse start value: {:?} / {}", s, e).to_string()))),
-        },
-        None => 0,
-    };
-
-    let end = match query.get("end") {
-        Some(s) => match s[0].parse() {
-            Ok(o) => match o {
-                o if o >= logs.len() => logs.len() - 1,
-                o => o,
-            },
-            Err(e) => return Ok(Response::with((status::BadRequest, format!("Unable to parse end value: {:?} / {}", s, e).to_string()))),
-        },
-        None => logs.len(),
-    };
+fn show_protocol_errors_json(_: &mut Request, state: &Arc<Mutex<InterfaceState>>) -> IronResult<Response> {
+    let state = state.lock().unwrap();
 
-    Ok(Response::with((content_type, status::Ok, serde_json::to_string(&logs[start..end]).unwrap())))
-}
-
-fn show_current_logs_json(request: &mut Request, state: &Arc<Mutex<InterfaceState>>) -> IronResult<Response> {
     let content_type = "application/json".parse::<Mime>().unwrap();
-    let query = match request.get_ref::<urlencoded::UrlEncodedQuery>() {
-        Ok(hashmap) => hashmap.clone(),
-        Err(_) => HashMap::new(),
-    };
-
-    let ref state = *state.lock().unwrap();
-
-    let start = match query.get("start") {
-        Some(s) => match s[0].parse() {
-            Ok(o) => match o {
-                o if o >= state.current_log.len() => return Ok(Response::with((content_type, status::Ok, "[]".to_string()))),
-                o => o,
-            },
-            Err(e) => return Ok(Response::with((status::BadRequest, format!("Unable to parse start value: {:?} / {}", s, e).to_string()))),
-        },
-        None => 0,
-    };
-
-    let end = match query.get("end") {
-        Some(s) => match s[0].parse() {
-            Ok(o) => match o {
-                o if o >= state.current_log.len() => state.current_log.len() - 1,
-                o => o,
-            },
-            Err(e) => return Ok(Response::with((status::BadRequest, format!("Unable to parse end value: {:?} / {}", s, e).to_string()))),
-        },
-        None => state.current_log.len(),
-    };
-
-    Ok(Response::with((content_type, status::Ok, serde_json::to_string(&state.current_log[start..end]).unwrap())))
+    Ok(Response::with((content_type, status::Ok, serde_json::to_string(&state.protocol_errors).unwrap())))
 }
 
-fn show_previous_logs_json(request: &mut Request, state: &Arc<Mutex<InterfaceState>>) -> IronResult<Response> {
+fn truncate_logs(request: &mut Request, logs: &Arc<Mutex<LogStore>>) -> IronResult<Response> {
     let content_type = "application/json".parse::<Mime>().unwrap();
-    let query = match request.get_ref::<urlencoded::UrlEncodedQuery>() {
-        Ok(hashmap) => hashmap.clone(),
-        Err(_) => HashMap::new(),
-    };
+    logs.lock().unwrap().truncate();
 
-    let ref state = *state.lock().unwrap();
+    let response = Ok(Response::with((content_type, status::Ok, "{\"status\": \"ok\"}")));
+    audit::record_response(request, "truncate", &[], &response);
+    response
+}
 
-    let start = match query.get("start") {
-        Some(s) => match s[0].parse() {
-            Ok(o) => match o {
-                o if o >= state.previous_log.len() => return Ok(Response::with((content_type, status::Ok, "[]".to_string()))),
-                o => o,
-            },
-            Err(e) => return Ok(Response::with((status::BadRequest, format!("Unable to parse start value: {:?} / {}", s, e).to_string()))),
-        },
-        None => 0,
-    };
+/// Tell exclave to shut down, and quit once the current response has gone
+/// out and any exclave we started has stopped.
+fn shut_down(reason: String) {
+    cfti_send(OutgoingMessage::Shutdown(reason));
 
-    let end = match query.get("end") {
-        Some(s) => match s[0].parse() {
-            Ok(o) => match o {
-                o if o >= state.previous_log.len() => state.previous_log.len() - 1,
-                o => o,
-            },
-            Err(e) => return Ok(Response::with((status::BadRequest, format!("Unable to parse end value: {:?} / {}", s, e).to_string()))),
-        },
-        None => state.previous_log.len(),
-    };
+    thread::spawn(|| {
+        thread::sleep(time::Duration::from_millis(5));
+        supervisor::stop();
+        std::process::exit(0);
+    });
+}
 
-    Ok(Response::with((content_type, status::Ok, serde_json::to_string(&state.previous_log[start..end]).unwrap())))
+fn exit_server(request: &mut Request) -> IronResult<Response> {
+    let response = Ok(Response::with((status::Ok, "Server is shutting down".to_string())));
+    // Record it first, so that the audit entry reaches exclave before SHUTDOWN does.
+    audit::record_response(request, "exit", &[], &response);
+    shut_down("User clicked Quit".to_string());
+    response
 }
 
-fn truncate_logs(_request: &mut Request, state: &Arc<Mutex<Vec<LogMessage>>>) -> IronResult<Response> {
-    let content_type = "application/json".parse::<Mime>().unwrap();
-    let ref mut logs = *state.lock().unwrap();
-    logs.clear();
+fn send_hello(request: &mut Request) -> IronResult<Response> {
+    cfti_send(OutgoingMessage::Hello(SERVER_SIGNATURE.to_string()));
 
-    Ok(Response::with((content_type, status::Ok, "{status: \"ok\"}")))
+    let response = Ok(Response::with((status::Ok, "Sending HELLO".to_string())));
+    audit::record_response(request, "hello", &[], &response);
+    response
 }
 
-fn exit_server(_: &mut Request) -> IronResult<Response> {
-    cfti_send(OutgoingMessage::Shutdown("User clicked Quit".to_string()));
+/// Split "wait=<ms>" out of a control request's query string, returning
+/// the rest of the query (if any) along with how long to wait.
+fn split_wait(query: Option<&str>) -> Result<(Option<String>, Option<time::Duration>), Response> {
+    let mut rest = vec![];
+    let mut wait = None;
+    for part in query.unwrap_or("").split('&').filter(|p| !p.is_empty()) {
+        match part.strip_prefix("wait=") {
+            Some(ms) => match ms.parse::<u64>() {
+                Ok(ms) => wait = Some(time::Duration::from_millis(ms.min(MAX_WAIT_MS))),
+                Err(_) => return Err(Response::with((status::BadRequest, format!("Unable to parse wait value: {:?}", ms)))),
+            },
+            None => rest.push(part),
+        }
+    }
+    let rest = if rest.is_empty() { None } else { Some(rest.join("&")) };
+    Ok((rest, wait))
+}
 
-    thread::spawn(|| {
-        thread::sleep(time::Duration::from_millis(5));
-        std::process::exit(0);
-    });
-    Ok(Response::with((status::Ok, "Server is shutting down".to_string())))
+/// Send `message` to exclave, and wait up to `wait` for the reply that `wanted` accepts.
+fn send_and_await<F: Fn(&StateEvent) -> bool>(message: OutgoingMessage, wait: time::Duration, events: &Arc<Mutex<EventBus>>, wanted: F) -> Option<StateEvent> {
+    // Listen before sending, so that a quick reply can't be missed.
+    let subscription = events.lock().unwrap().subscribe_internal();
+    cfti_send(message);
+    subscription.wait_for(wait, wanted)
 }
 
-fn send_hello(_: &mut Request) -> IronResult<Response> {
-    cfti_send(OutgoingMessage::Hello(SERVER_SIGNATURE.to_string()));
+/// Send `message` to exclave.  Without `wait`, return `requesting` right away.
+/// With it, block until exclave's `reply` arrives and return it as JSON, or
+/// give up with a 504 once `wait` has passed.
+fn send_and_wait<F: Fn(&StateEvent) -> bool>(message: OutgoingMessage,
+                                             requesting: String,
+                                             wait: Option<time::Duration>,
+                                             events: &Arc<Mutex<EventBus>>,
+                                             reply: &str,
+                                             wanted: F) -> IronResult<Response> {
+    let wait = match wait {
+        None => {
+            cfti_send(message);
+            return Ok(Response::with((status::Ok, requesting)));
+        },
+        Some(w) => w,
+    };
 
-    Ok(Response::with((status::Ok, "Sending HELLO".to_string())))
+    match send_and_await(message, wait, events, wanted) {
+        Some(event) => {
+            let content_type = "application/json".parse::<Mime>().unwrap();
+            Ok(Response::with((content_type, status::Ok, serde_json::to_string(&event).unwrap())))
+        },
+        None => Ok(Response::with((status::GatewayTimeout,
+                                   format!("Timed out after {} ms waiting for {} from exclave", wait.as_millis(), reply)))),
+    }
 }
 
-fn send_scenarios(_: &mut Request) -> IronResult<Response> {
-    cfti_send(OutgoingMessage::Scenarios);
+fn send_scenarios(request: &mut Request, events: &Arc<Mutex<EventBus>>) -> IronResult<Response> {
+    let wait = match split_wait(request.url.query()) { Ok((_, w)) => w, Err(r) => return Ok(r) };
 
-    Ok(Response::with((status::Ok, "Sending SCENARIOS".to_string())))
+    send_and_wait(OutgoingMessage::Scenarios, "Sending SCENARIOS".to_string(), wait, events,
+                  "SCENARIOS", |e| matches!(e, StateEvent::Scenarios { .. }))
 }
 
 fn select_scenario(request: &mut Request) -> IronResult<Response> {
@@ -327,209 +421,587 @@ fn select_scenario(request: &mut Request) -> IronResult<Response> {
     };
 
     cfti_send(OutgoingMessage::Scenario(scenario_id.clone()));
-    Ok(Response::with((status::Ok, format!("Selecting scenario {}", scenario_id).to_string())))
+    let response = Ok(Response::with((status::Ok, format!("Selecting scenario {}", scenario_id).to_string())));
+    audit::record_response(request, "scenario", &[("scenario", scenario_id)], &response);
+    response
 }
 
-fn get_jig(_: &mut Request) -> IronResult<Response> {
-    cfti_send(OutgoingMessage::GetJig);
+fn get_jig(request: &mut Request, events: &Arc<Mutex<EventBus>>) -> IronResult<Response> {
+    let wait = match split_wait(request.url.query()) { Ok((_, w)) => w, Err(r) => return Ok(r) };
 
-    Ok(Response::with((status::Ok, "Requesting jig id".to_string())))
+    send_and_wait(OutgoingMessage::GetJig, "Requesting jig id".to_string(), wait, events,
+                  "JIG", |e| matches!(e, StateEvent::Jig { .. }))
 }
 
-fn get_tests(_: &mut Request) -> IronResult<Response> {
-    cfti_send(OutgoingMessage::GetTests);
+fn get_tests(request: &mut Request, events: &Arc<Mutex<EventBus>>) -> IronResult<Response> {
+    let wait = match split_wait(request.url.query()) { Ok((_, w)) => w, Err(r) => return Ok(r) };
 
-    Ok(Response::with((status::Ok, "Requesting test list".to_string())))
+    send_and_wait(OutgoingMessage::GetTests, "Requesting test list".to_string(), wait, events,
+                  "TESTS", |e| matches!(e, StateEvent::Tests { .. }))
 }
 
-fn start_tests(request: &mut Request, state: &Arc<Mutex<InterfaceState>>) -> IronResult<Response> {
-    let scenario_id = match request.url.query() {
+fn start_tests(request: &mut Request, state: &Arc<Mutex<InterfaceState>>, events: &Arc<Mutex<EventBus>>) -> IronResult<Response> {
+    let (scenario_id, wait) = match split_wait(request.url.query()) { Ok(v) => v, Err(r) => return Ok(r) };
+    let scenario_id = match scenario_id {
         None => state.lock().unwrap().scenario.clone(),
-        Some(s) => s.to_string(),
+        Some(s) => s,
     };
 
-    cfti_send(OutgoingMessage::StartTests(scenario_id.clone()));
-
-    Ok(Response::with((status::Ok, format!("Starting {} scenario", scenario_id))))
+    let response = send_and_wait(OutgoingMessage::StartTests(scenario_id.clone()), format!("Starting {} scenario", scenario_id), wait, events,
+                                 "START", |e| matches!(e, StateEvent::Start { .. }));
+    audit::record_response(request, "start", &[("scenario", scenario_id)], &response);
+    response
 }
 
-fn abort_tests(_: &mut Request) -> IronResult<Response> {
+fn abort_tests(request: &mut Request) -> IronResult<Response> {
     cfti_send(OutgoingMessage::AbortTests);
 
-    Ok(Response::with((status::Ok, "Aborting tests".to_string())))
+    let response = Ok(Response::with((status::Ok, "Aborting tests".to_string())));
+    audit::record_response(request, "abort", &[], &response);
+    response
 }
 
-fn stdin_describe(data: &mut InterfaceState, items: Vec<String>) {
-    let mut rest = items.clone();
-    let class = rest.remove(0).to_lowercase();
-    let field = rest.remove(0).to_lowercase();
-    let name = if rest.len() > 0 {
-        rest.remove(0)
-    } else {
-        "No Name".to_string()
-    };
+fn stdin_describe(data: &mut InterfaceState, class: DescribeClass, field: DescribeField, name: String, value: String) -> StateEvent {
     let name_lc = name.to_lowercase();
-    let value = rest.join(" ");
 
-    match class.as_str() {
-        "test" => match field.as_str() {
-            "name" => {data.test_names.insert(name_lc, value);},
-            "description" => {data.test_descriptions.insert(name_lc, value);},
-            f => eprintln!("Unrecognized field: {}", f),
+    let event = StateEvent::Describe {
+        class: class.name().to_string(),
+        field: field.name().to_string(),
+        name: name_lc.clone(),
+        value: value.clone(),
+    };
+
+    match class {
+        DescribeClass::Test => match field {
+            DescribeField::Name => {data.test_names.insert(name_lc, value);},
+            DescribeField::Description => {data.test_descriptions.insert(name_lc, value);},
         },
-        "scenario" => match field.as_str() {
-            "name" => {data.scenario_names.insert(name_lc, value);},
-            "description" => {data.scenario_descriptions.insert(name_lc, value);},
-            f => eprintln!("Unrecognized field: {}", f),
+        DescribeClass::Scenario => match field {
+            DescribeField::Name => {data.scenario_names.insert(name_lc, value);},
+            DescribeField::Description => {data.scenario_descriptions.insert(name_lc, value);},
         },
-        "jig" => match field.as_str() {
-            "name" => {data.jig_name = value;},
-            "description" => {data.jig_description = value;},
-            f => eprintln!("Unrecognized field: {}", f),
+        DescribeClass::Jig => match field {
+            DescribeField::Name => {data.jig_name = value;},
+            DescribeField::Description => {data.jig_description = value;},
         },
-        c => eprintln!("Unrecognized class: {}", c),
     };
+    event
 }
 
-fn stdin_monitor(data_arc: Arc<Mutex<InterfaceState>>, logs: Arc<Mutex<Vec<LogMessage>>>) {
-    let rx = io::stdin();
-    loop {
-        let mut line = String::new();
-        rx.read_line(&mut line).ok().expect("Unable to read line");
-        let ref mut data = *data_arc.lock().unwrap();
-        if data.log_stdin {
-            data.stdin_log.push(line.clone());
-        }
-        let line = cfti_unescape(line);
-
-        let mut items: Vec<String> = line.split_whitespace().map(|x| x.to_string()).collect();
-        //eprintln!("Got command: {:?}", items);
-        let verb = items[0].to_lowercase();
-        items.remove(0);
-
-        match verb.as_str() {
-            "hello" => data.server = items.join(" "),
-            "jig" => data.jig = items.get(0).unwrap_or(&"No Jig".to_string()).clone(),
-            "scenarios" => data.scenarios = items.clone(),
-            "scenario" => {
-                data.scenario = items.get(0).unwrap_or(&"No Scenario".to_string()).clone();
-                data.scenario_state = ScenarioState::Pending;
-            },
-            "tests" => {
-                let scenario_name = items.remove(0); // Remove the scenario name, which is the first result.
-                data.tests.insert(scenario_name, items.clone());
-
-                // We got a new set of tests, so reset all the test results to "Pending".
-                data.test_results.clear();
-                for item in items {
-                    data.test_results.insert(item, TestResult::Pending);
-                }
-            },
-            "describe" => stdin_describe(data, items),
-            "ping" => cfti_send(OutgoingMessage::Pong(items.get(0).unwrap_or(&"".to_string()).clone())),
-            "start" => {
-                let scenario_name = items.remove(0);
-                let ref mut data = *data;
-                data.scenario_state = ScenarioState::Running;
-
-                // We got a new set of tests, so reset all the test results to "Pending".
-                data.test_results.clear();
-                for test_name in &data.tests[&scenario_name] {
+/// Record the result of a test, and how long it took.
+fn stdin_test_finished(data: &mut InterfaceState, test_id: String, result: TestResult) -> StateEvent {
+    let now = unix_time();
+    let timing = {
+        let timing = data.test_timings.entry(test_id.clone()).or_default();
+        timing.finished = Some(now);
+        timing.duration = timing.started.and_then(|started| now.checked_sub(started));
+        timing.clone()
+    };
+    data.test_results.insert(test_id.clone(), result.clone());
+    StateEvent::TestResult { test: test_id, result, timing }
+}
+
+/// If a run was started but never finished, save what we know of it to the
+/// history, and mark it as ended so that it isn't saved again.
+fn save_unfinished_run(data: &mut InterfaceState, logs: &Mutex<LogStore>, history: &Mutex<RunHistory>) {
+    let summary = match data.run {
+        Some(ref run) if run.finished.is_none() => RunSummary::from_state(data, run),
+        _ => return,
+    };
+    history.lock().unwrap().add(summary, &LogStore::view(logs, LogView::CurrentRun));
+
+    if let Some(ref mut run) = data.run {
+        let now = unix_time();
+        run.finished = Some(now);
+        run.duration = now.checked_sub(run.started);
+    }
+}
+
+/// Apply a message from the server to the interface state, returning the resulting change.
+fn stdin_apply(data: &mut InterfaceState, logs: &Arc<Mutex<LogStore>>, history: &Arc<Mutex<RunHistory>>, message: IncomingMessage) -> Option<StateEvent> {
+    match message {
+        IncomingMessage::Hello(server) => {
+            data.server = server.clone();
+            Some(StateEvent::Hello { server })
+        },
+        IncomingMessage::Jig(jig) => {
+            data.jig = jig.clone();
+            Some(StateEvent::Jig { jig })
+        },
+        IncomingMessage::Scenarios(scenarios) => {
+            data.scenarios = scenarios.clone();
+            Some(StateEvent::Scenarios { scenarios })
+        },
+        IncomingMessage::Scenario(scenario) => {
+            data.scenario = scenario.clone();
+            data.scenario_state = ScenarioState::Pending;
+            Some(StateEvent::Scenario { scenario })
+        },
+        IncomingMessage::Tests(scenario, tests) => {
+            data.tests.insert(scenario.clone(), tests.clone());
+
+            // We got a new set of tests, so reset all the test results to "Pending".
+            data.test_results.clear();
+            data.test_timings.clear();
+            for test in &tests {
+                data.test_results.insert(test.clone(), TestResult::Pending);
+            }
+            Some(StateEvent::Tests { scenario, tests })
+        },
+        IncomingMessage::Describe(class, field, name, value) => Some(stdin_describe(data, class, field, name, value)),
+        IncomingMessage::Ping(token) => {
+            cfti_send(OutgoingMessage::Pong(token));
+            None
+        },
+        IncomingMessage::Start(scenario) => {
+            // Don't lose the previous run if it never sent FINISH.
+            save_unfinished_run(data, logs, history);
+
+            // The identity that was entered belongs to this run, and the next
+            // unit will need one of its own.
+            let identity = data.identity.take();
+            if let Some(ref identity) = identity {
+                cfti_send(OutgoingMessage::Log(identity.describe()));
+            }
+            data.run = Some(CurrentRun {
+                id: history.lock().unwrap().allocate_id(),
+                scenario: scenario.clone(),
+                started: unix_time(),
+                finished: None,
+                duration: None,
+                result: None,
+                identity,
+            });
+            data.scenario_state = ScenarioState::Running;
+
+            // We got a new set of tests, so reset all the test results to "Pending".
+            // A scenario we were never told the tests for simply has none.
+            data.test_results.clear();
+            data.test_timings.clear();
+            if let Some(tests) = data.tests.get(&scenario) {
+                for test_name in tests {
                     data.test_results.insert(test_name.clone(), TestResult::Pending);
                 }
+            }
 
-                // Move "current_log" to "previous_log", and truncate "current_log"
-                data.previous_log.clear();
-                for element in data.current_log.drain(..) {
-                    data.previous_log.push(element);
-                }
-            },
-            "finish" => {
-                let result = match items.remove(1).parse() {
-                    Ok(val) => val,
-                    Err(e) => {eprintln!("Unable to parse result: {:?}", e); 500},
-                };
-
-                data.scenario_state = match result {
-                    // Only results of 200 to 299 are considered "pass"
-                    200 ..= 299 => ScenarioState::Pass,
-                    _ => ScenarioState::Fail,
-                };
+            // The current run's logs become the previous run's.
+            logs.lock().unwrap().start_run();
+            Some(StateEvent::Start { scenario })
+        },
+        IncomingMessage::Finish(scenario, result, _reason) => {
+            data.scenario_state = if data.pass_codes.contains(&result) {
+                ScenarioState::Pass
+            } else {
+                ScenarioState::Fail
+            };
+
+            // Record the run.  A FINISH without a START has nothing to record.
+            let finished_run = match data.run {
+                Some(ref mut run) if run.finished.is_none() => {
+                    let now = unix_time();
+                    run.finished = Some(now);
+                    run.duration = now.checked_sub(run.started);
+                    run.result = Some(result);
+                    Some(run.clone())
+                },
+                _ => None,
+            };
+            let duration = finished_run.as_ref().and_then(|run| run.duration);
+            if let Some(run) = finished_run {
+                let summary = RunSummary::from_state(data, &run);
+                history.lock().unwrap().add(summary, &LogStore::view(logs, LogView::CurrentRun));
             }
-            "running" => {
-                let test_id = items.remove(0);
-                data.test_results.insert(test_id, TestResult::Running);
-            },
-            "pass" => {
-                let test_id = items.remove(0);
-                let test_result = items.join(" ");
-                data.test_results.insert(test_id, TestResult::Pass(test_result));
-            },
-            "fail" => {
-                let test_id = items.remove(0);
-                let test_result = items.join(" ");
-                data.test_results.insert(test_id, TestResult::Fail(test_result));
-            },
-            "skip" => {
-                let test_id = items.remove(0);
-                let test_result = items.join(" ");
-                data.test_results.insert(test_id, TestResult::Skipped(test_result));
-            },
-            "log" => {
-                let message_class = items.remove(0);
-                let unit_id = items.remove(0);
-                let unit_type = items.remove(0);
-
-                // Pull the timestamp out of the subsequent elements and parse them.
-                let timestamp = time::Duration::new(items[0].parse().unwrap(),
-                                                    items[1].parse().unwrap());
-                items.remove(0); // Remove secs
-                items.remove(0); // Remove nsecs
-
-                let message = items.join(" ");
-                let log_message = LogMessage {
-                    message_class: message_class,
-                    unit_id: unit_id,
-                    unit_type: unit_type,
-                    timestamp: timestamp,
-                    message: message,
-                };
-
-                // Add the message to the global list of logs.
-                logs.lock().unwrap().push(log_message.clone());
-
-                // Also add the new message to the list of current log messages.
-                data.current_log.push(log_message);
-            },
-            "exit" => std::process::exit(0),
-            other => eprintln!("Unrecognized command: {}", other),
+
+            Some(StateEvent::Finish {
+                scenario,
+                result,
+                scenario_state: data.scenario_state.clone(),
+                duration,
+            })
+        },
+        IncomingMessage::Running(test_id) => {
+            let timing = TestTiming {
+                started: Some(unix_time()),
+                finished: None,
+                duration: None,
+            };
+            data.test_results.insert(test_id.clone(), TestResult::Running);
+            data.test_timings.insert(test_id.clone(), timing.clone());
+            Some(StateEvent::TestResult { test: test_id, result: TestResult::Running, timing })
+        },
+        IncomingMessage::Pass(test_id, message) => Some(stdin_test_finished(data, test_id, TestResult::Pass(message))),
+        IncomingMessage::Fail(test_id, message) => Some(stdin_test_finished(data, test_id, TestResult::Fail(message))),
+        IncomingMessage::Skip(test_id, message) => Some(stdin_test_finished(data, test_id, TestResult::Skipped(message))),
+        IncomingMessage::Log(message_class, unit_id, unit_type, timestamp, message) => {
+            let log_message = LogMessage {
+                seq: 0,
+                message_class,
+                unit_id,
+                unit_type,
+                timestamp,
+                message,
+            };
+
+            // Add the message to the log, which gives it its sequence number.
+            let log_message = logs.lock().unwrap().push(log_message);
+            Some(StateEvent::Log { log: log_message })
+        },
+        IncomingMessage::Exit => {
+            supervisor::stop();
+            std::process::exit(0);
+        },
+    }
+}
+
+/// Applies what the server says to the interface state.
+struct Monitor {
+    state: Arc<Mutex<InterfaceState>>,
+    logs: Arc<Mutex<LogStore>>,
+    events: Arc<Mutex<EventBus>>,
+    history: Arc<Mutex<RunHistory>>,
+    metrics: Arc<Mutex<metrics::Metrics>>,
+    policy: DisconnectPolicy,
+
+    /// Every line received is also given to this, if there is one.
+    recorder: Option<replay::Recorder>,
+}
+
+impl Monitor {
+    /// Apply lines from `reader` until the server goes away, and return why
+    /// it did.  `name` is what to call the server's end in messages, such as "stdin".
+    fn read<R: BufRead>(&mut self, mut reader: R, name: &str) -> String {
+        loop {
+            let mut raw = vec![];
+            match reader.read_until(b'\n', &mut raw) {
+                Ok(0) => return format!("Reached the end of {}", name),
+                Ok(_) => (),
+                Err(e) => return format!("Unable to read from {}: {}", name, e),
+            }
+            if let Some(ref mut recorder) = self.recorder {
+                recorder.record(&String::from_utf8_lossy(&raw));
+            }
+            let data = &mut *self.state.lock().unwrap();
+            data.last_line = Some(unix_time());
+
+            let line = match String::from_utf8(raw) {
+                Ok(l) => l,
+                Err(e) => {
+                    let line = String::from_utf8_lossy(e.as_bytes()).into_owned();
+                    trace::record(trace::Direction::Incoming, &line, || format!("error: {}", ProtocolError::InvalidEncoding));
+                    eprintln!("Rejected line {:?}: {}", line, ProtocolError::InvalidEncoding);
+                    data.protocol_errors.record(&line, ProtocolError::InvalidEncoding);
+                    continue;
+                },
+            };
+
+            if data.log_stdin {
+                data.stdin_log.push(line.clone());
+            }
+
+            // Blank lines carry no meaning, so don't count them as errors.
+            if line.trim().is_empty() {
+                trace::record(trace::Direction::Incoming, &line, || "blank line".to_string());
+                continue;
+            }
+
+            let message = IncomingMessage::parse(&line);
+            trace::record(trace::Direction::Incoming, &line, || match message {
+                Ok((ref m, None)) => format!("{:?}", m),
+                Ok((ref m, Some(ref e))) => format!("{:?} (error: {})", m, e),
+                Err(ref e) => format!("error: {}", e),
+            });
+            let message = match message {
+                Ok((m, None)) => m,
+                Ok((m, Some(e))) => {
+                    eprintln!("Accepted line {:?} in spite of an error: {}", line, e);
+                    data.protocol_errors.record(&line, e);
+                    m
+                },
+                Err(e) => {
+                    eprintln!("Rejected line {:?}: {}", line, e);
+                    data.protocol_errors.record(&line, e);
+                    continue;
+                },
+            };
+
+            // Let anyone watching know what changed.  The metrics are counted
+            // here rather than from the event bus, which may drop listeners.
+            if let Some(event) = stdin_apply(data, &self.logs, &self.history, message) {
+                self.metrics.lock().unwrap().observe(&event);
+                self.events.lock().unwrap().publish(event);
+            }
+        }
+    }
+
+    /// Note that the server has connected, or reconnected.
+    fn connected(&self) {
+        let data = &mut *self.state.lock().unwrap();
+        data.connection = ConnectionStatus::Connected { since: unix_time() };
+        self.events.lock().unwrap().publish(StateEvent::Connection { connection: data.connection.clone() });
+    }
+
+    /// Note that the server has gone away, and do what `policy` says.
+    fn disconnected(&self, reason: String) {
+        eprintln!("Lost connection to the server: {}", reason);
+        {
+            let data = &mut *self.state.lock().unwrap();
+            save_unfinished_run(data, &self.logs, &self.history);
+            data.connection = ConnectionStatus::Disconnected {
+                since: unix_time(),
+                reason,
+            };
+            self.events.lock().unwrap().publish(StateEvent::Connection { connection: data.connection.clone() });
+        }
+
+        if self.policy == DisconnectPolicy::Exit {
+            std::process::exit(0);
         }
     }
 }
 
-fn main() {
-    let mut mnt = Mount::new();
-    let staticfile = Static::new("html");
+/// Read the server's messages from `reader`, which only ever connects once.
+fn stdin_monitor<R: BufRead>(mut monitor: Monitor, reader: R, name: &str) {
+    let reason = monitor.read(reader, name);
+    monitor.disconnected(reason);
+}
 
+fn main() {
     let matches = App::new("Jig-20 HTTP Interface")
                         .version("1.0")
                         .author("Sean Cross <sean@xobs.io>")
                         .about("Presents CFTI over a web server")
+                        .arg(Arg::with_name("CONFIG")
+                                .short("c")
+                                .long("config")
+                                .value_name("FILE")
+                                .help("Read settings from this TOML file.  Options given on the command line override it.")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("PRINT_CONFIG")
+                                .long("print-config")
+                                .help("Print the settings from --config and the command line as TOML, and exit")
+                        )
                         .arg(Arg::with_name("ADDRESS")
                                 .short("a")
                                 .long("address")
                                 .value_name("LISTEN_ADDRESS")
-                                .help("Interface address to listen on")
-                                .default_value("0.0.0.0")
-                                .required(true)
+                                .help("Interface address to listen on (defaults to 0.0.0.0)")
+                                .takes_value(true)
                         )
                         .arg(Arg::with_name("PORT")
                                 .short("p")
                                 .long("port")
                                 .value_name("PORT_NUMBER")
-                                .help("Port to listen on")
-                                .default_value("3000")
-                                .required(true)
+                                .help("Port to listen on (defaults to 3000)")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("WS_PORT")
+                                .short("w")
+                                .long("ws-port")
+                                .value_name("PORT_NUMBER")
+                                .help("Port to accept WebSocket connections on (off unless given)")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("HTML_ROOT")
+                                .long("html-root")
+                                .value_name("DIRECTORY")
+                                .help("Directory to serve the web pages from (defaults to html)")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("ON_DISCONNECT")
+                                .long("on-disconnect")
+                                .value_name("POLICY")
+                                .help("What to do when stdin is closed: exit, or stay up and keep serving the last-known state (defaults to stay)")
+                                .possible_values(&["exit", "stay"])
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("HISTORY")
+                                .long("history")
+                                .value_name("HISTORY_FILE")
+                                .help("File to keep the results of every run in, across restarts")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("STDF_DIR")
+                                .long("stdf-dir")
+                                .value_name("DIRECTORY")
+                                .help("Directory to write an STDF V4 file for each run into")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("STDF_LOT")
+                                .long("stdf-lot")
+                                .value_name("LOT_ID")
+                                .help("Write every run into a single STDF file for this lot, rather than one file per run")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("STATION_NAME")
+                                .long("station-name")
+                                .value_name("NAME")
+                                .help("Name of this test station, recorded with every run")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("STATION_NUMBER")
+                                .long("station-number")
+                                .value_name("NUMBER")
+                                .help("Number of this test station, from 0 to 255, recorded with every run")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("PASS_MIN")
+                                .long("pass-min")
+                                .value_name("RESULT")
+                                .help("Lowest FINISH result that counts as a pass (defaults to 200)")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("PASS_MAX")
+                                .long("pass-max")
+                                .value_name("RESULT")
+                                .help("Highest FINISH result that counts as a pass (defaults to 299)")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("SERIAL_PATTERN")
+                                .long("serial-pattern")
+                                .value_name("REGEX")
+                                .help("Regular expression that DUT serial numbers must match in full")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("LOG_MEMORY_COUNT")
+                                .long("log-memory-count")
+                                .value_name("MESSAGES")
+                                .help("Most log messages to keep in memory (defaults to 100000)")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("LOG_MEMORY_BYTES")
+                                .long("log-memory-bytes")
+                                .value_name("BYTES")
+                                .help("Most memory to use for log messages (defaults to 67108864)")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("LOG_SPILL_DIR")
+                                .long("log-spill-dir")
+                                .value_name("DIRECTORY")
+                                .help("Directory to move log messages into once they no longer fit in memory, rather than dropping them")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("TRANSPORT")
+                                .short("t")
+                                .long("transport")
+                                .value_name("TRANSPORT")
+                                .help("How to talk to exclave: stdio, tcp-listen:ADDRESS:PORT, tcp-connect:HOST:PORT, unix-listen:PATH, or unix-connect:PATH (defaults to stdio)")
+                                .conflicts_with_all(&["REPLAY", "SIMULATE"])
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("EXCLAVE")
+                                .long("exclave")
+                                .value_name("COMMAND")
+                                .help("Start exclave with this command line and talk to it over its stdin and stdout, restarting it if it stops")
+                                .conflicts_with_all(&["TRANSPORT", "REPLAY", "SIMULATE"])
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("EXCLAVE_TESTS")
+                                .long("exclave-tests")
+                                .value_name("DIRECTORY")
+                                .help("Tests directory to run exclave in")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("RECORD")
+                                .long("record")
+                                .value_name("FILE")
+                                .help("Record every line received from the server, with its timing, for use with --replay")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("REPLAY")
+                                .long("replay")
+                                .value_name("FILE")
+                                .help("Play back a file made by --record instead of reading from stdin")
+                                .conflicts_with("RECORD")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("REPLAY_SPEED")
+                                .long("replay-speed")
+                                .value_name("FACTOR")
+                                .help("How many times faster than real time to replay, or 0 to replay without waiting (defaults to 1)")
+                                .requires("REPLAY")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("SIMULATE")
+                                .long("simulate")
+                                .help("Talk to a built-in simulated jig instead of exclave")
+                                .conflicts_with("REPLAY")
+                        )
+                        .arg(Arg::with_name("SIMULATE_FAIL_RATE")
+                                .long("simulate-fail-rate")
+                                .value_name("PROBABILITY")
+                                .help("Chance of each simulated test failing, from 0 to 1 (defaults to 0.1)")
+                                .requires("SIMULATE")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("SIMULATE_SKIP_RATE")
+                                .long("simulate-skip-rate")
+                                .value_name("PROBABILITY")
+                                .help("Chance of each simulated test being skipped, from 0 to 1 (defaults to 0.05)")
+                                .requires("SIMULATE")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("SIMULATE_TEST_DURATION")
+                                .long("simulate-test-duration")
+                                .value_name("SECONDS")
+                                .help("Average time each simulated test takes (defaults to 2)")
+                                .requires("SIMULATE")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("SIMULATE_SEED")
+                                .long("simulate-seed")
+                                .value_name("NUMBER")
+                                .help("Seed for the simulated test outcomes, to repeat a previous session (defaults to the time)")
+                                .requires("SIMULATE")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("TRACE")
+                                .long("trace")
+                                .help("Start with protocol tracing turned on (see /protocol/trace)")
+                        )
+                        .arg(Arg::with_name("TRACE_ENTRIES")
+                                .long("trace-entries")
+                                .value_name("ENTRIES")
+                                .help("Most lines to keep in the protocol trace (defaults to 10000)")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("LEGACY_GET")
+                                .long("legacy-get")
+                                .help("Also accept the old GET control routes, such as /start and /exit, alongside /api/v1")
+                        )
+                        .arg(Arg::with_name("AUTH")
+                                .long("auth")
+                                .value_name("FILE")
+                                .help("Require logging in, with the users, tokens, and roles listed in this JSON file")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("TLS_CERT")
+                                .long("tls-cert")
+                                .value_name("FILE")
+                                .help("Serve HTTPS with this PEM certificate chain (reloaded on SIGHUP)")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("TLS_KEY")
+                                .long("tls-key")
+                                .value_name("FILE")
+                                .help("PEM private key for --tls-cert")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("TLS_CLIENT_CA")
+                                .long("tls-client-ca")
+                                .value_name("FILE")
+                                .help("Require every client to present a certificate signed by a CA in this PEM bundle (mutual TLS)")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("AUDIT_LOG")
+                                .long("audit-log")
+                                .value_name("FILE")
+                                .help("Append every control action to this file, one JSON object per line")
+                                .takes_value(true)
+                        )
+                        .arg(Arg::with_name("HASH_PASSWORD")
+                                .long("hash-password")
+                                .help("Read a password from stdin, print its hash for the --auth file, and exit")
                         )
                         .arg(Arg::with_name("LOG_STDIN")
                                 .short("l")
@@ -538,11 +1010,140 @@ fn main() {
                         )
                         .get_matches();
 
-    let interface = matches.value_of("ADDRESS").unwrap();
-    let port = matches.value_of("PORT").unwrap();
+    if matches.is_present("HASH_PASSWORD") {
+        let mut password = String::new();
+        if let Err(e) = io::stdin().read_line(&mut password) {
+            eprintln!("Unable to read the password: {}", e);
+            std::process::exit(1);
+        }
+        match auth::hash_password(password.trim_end_matches(&['\r', '\n'][..])) {
+            Ok(hash) => println!("{}", hash),
+            Err(e) => { eprintln!("{}", e); std::process::exit(1); },
+        }
+        return;
+    }
+
+    let mut config = match matches.value_of("CONFIG") {
+        None => config::Config::default(),
+        Some(path) => match config::Config::load(path) {
+            Ok(c) => c,
+            Err(e) => { eprintln!("Unable to load config file {}: {}", path, e); std::process::exit(1); },
+        },
+    };
+    config.apply_arguments(&matches);
+    if let Err(e) = config.check() {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    }
+    if matches.is_present("PRINT_CONFIG") {
+        print!("{}", config.to_toml());
+        return;
+    }
+
+    let staticfile = Static::new(&config.server.html_root);
+    let auth = match config.auth.file {
+        None => None,
+        Some(ref path) => match auth::Auth::load(path) {
+            Ok(a) => Some(Arc::new(a)),
+            Err(e) => { eprintln!("Unable to load {}: {}", path, e); std::process::exit(1); },
+        },
+    };
+    let tls = match (config.tls.cert.as_deref(), config.tls.key.as_deref()) {
+        (Some(cert), Some(key)) => match tls::TlsServer::new(cert, key, config.tls.client_ca.as_deref()) {
+            Ok(t) => Some(t),
+            Err(e) => { eprintln!("Unable to set up TLS: {}", e); std::process::exit(1); },
+        },
+        _ => None,
+    };
+    if let Some(ref path) = config.storage.audit_log {
+        if let Err(e) = audit::open(path) {
+            eprintln!("Unable to open audit log {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    let serial_pattern = match config.units.serial_pattern {
+        None => None,
+        Some(ref p) => match identity::serial_pattern(p) {
+            Ok(r) => Some(r),
+            Err(e) => { eprintln!("Invalid serial number pattern {}: {}", p, e); std::process::exit(1); },
+        },
+    };
+    let replay_speed = match matches.value_of("REPLAY_SPEED") {
+        None => 1.0,
+        Some(_) => value_t_or_exit!(matches, "REPLAY_SPEED", f64),
+    };
+    // A speed so close to 0 that its reciprocal overflows would make every
+    // wait overflow too.
+    if !replay_speed.is_finite() || replay_speed < 0.0 || (replay_speed > 0.0 && !replay_speed.recip().is_finite()) {
+        eprintln!("Invalid replay speed {:?}: it must be 0, or a finite number that isn't vanishingly small", replay_speed);
+        std::process::exit(1);
+    }
+    let replay = match matches.value_of("REPLAY") {
+        None => None,
+        Some(path) => match replay::ReplayReader::open(Path::new(path), replay_speed) {
+            Ok(r) => Some((path.to_string(), r)),
+            Err(e) => { eprintln!("Unable to open replay file {}: {}", path, e); std::process::exit(1); },
+        },
+    };
+    let simulator_options = simulator::SimulatorOptions {
+        fail_rate: match matches.value_of("SIMULATE_FAIL_RATE") {
+            None => 0.1,
+            Some(_) => value_t_or_exit!(matches, "SIMULATE_FAIL_RATE", f64),
+        },
+        skip_rate: match matches.value_of("SIMULATE_SKIP_RATE") {
+            None => 0.05,
+            Some(_) => value_t_or_exit!(matches, "SIMULATE_SKIP_RATE", f64),
+        },
+        test_duration: match matches.value_of("SIMULATE_TEST_DURATION") {
+            None => time::Duration::from_secs(2),
+            Some(_) => match time::Duration::try_from_secs_f64(value_t_or_exit!(matches, "SIMULATE_TEST_DURATION", f64)) {
+                Ok(d) => d,
+                Err(e) => { eprintln!("Invalid simulated test duration: {}", e); std::process::exit(1); },
+            },
+        },
+        seed: match matches.value_of("SIMULATE_SEED") {
+            None => unix_time().as_nanos() as u64,
+            Some(_) => value_t_or_exit!(matches, "SIMULATE_SEED", u64),
+        },
+    };
+    if !(0.0..=1.0).contains(&simulator_options.fail_rate) || !(0.0..=1.0).contains(&simulator_options.skip_rate) {
+        eprintln!("Simulated fail and skip rates must be from 0 to 1");
+        std::process::exit(1);
+    }
+    let recorder = match matches.value_of("RECORD") {
+        None => None,
+        Some(path) => match replay::Recorder::create(Path::new(path)) {
+            Ok(r) => Some(r),
+            Err(e) => { eprintln!("Unable to create recording {}: {}", path, e); std::process::exit(1); },
+        },
+    };
+    let transport = match config.exclave.command {
+        Some(ref command_line) => match supervisor::ExclaveCommand::new(command_line, config.exclave.tests.as_deref()) {
+            Ok(c) => {
+                if let Err(e) = supervisor::shut_down_on_signal() {
+                    eprintln!("Unable to watch for SIGINT and SIGTERM, so exclave may be left running: {}", e);
+                }
+                transport::Transport::supervise(c)
+            },
+            Err(e) => { eprintln!("{}", e); std::process::exit(1); },
+        },
+        None => match transport::Transport::open(config.exclave.transport.as_deref().unwrap_or("stdio")) {
+            Ok(t) => t,
+            Err(e) => { eprintln!("{}", e); std::process::exit(1); },
+        },
+    };
+
+    trace::configure(config.logs.trace, config.logs.trace_entries);
 
     let state = Arc::new(Mutex::new(InterfaceState {
         server: "".to_string(),
+        station: config.station.clone(),
+        connection: if transport.is_stdio() {
+            ConnectionStatus::Connected { since: unix_time() }
+        } else {
+            ConnectionStatus::Disconnected { since: unix_time(), reason: "Waiting for the server to connect".to_string() }
+        },
+        exclave: None,
         jig: "".to_string(),
         jig_name: "".to_string(),
         jig_description: "".to_string(),
@@ -550,20 +1151,60 @@ fn main() {
         scenario_names: HashMap::new(),
         scenario_descriptions: HashMap::new(),
         scenario: "".to_string(),
+        identity: None,
         scenario_state: ScenarioState::Pending,
+        run: None,
         tests: HashMap::new(),
         test_names: HashMap::new(),
         test_descriptions: HashMap::new(),
         test_results: HashMap::new(),
+        test_timings: HashMap::new(),
         stdin_log: vec![],
-        log_stdin: matches.is_present("LOG_STDIN"),
-        current_log: vec![],
-        previous_log: vec![],
+        log_stdin: config.logs.log_stdin,
+        protocol_errors: ProtocolErrors::new(),
+        last_line: None,
+        pass_codes: config.pass_codes(),
     }));
 
-    let logs = Arc::new(Mutex::new(vec![]));
+    let mut logs = LogStore::new(config.logs.memory_count, config.logs.memory_bytes);
+    if let Some(ref dir) = config.logs.spill_dir {
+        if let Err(e) = logs.spill_to(Path::new(dir)) {
+            eprintln!("Unable to use log spill directory {}: {}", dir, e);
+            std::process::exit(1);
+        }
+    }
+    let logs = Arc::new(Mutex::new(logs));
+    let mut history = match config.storage.history {
+        None => RunHistory::new(),
+        Some(ref path) => match RunHistory::open(Path::new(path)) {
+            Ok(h) => h,
+            Err(e) => { eprintln!("Unable to open history file {}: {}", path, e); std::process::exit(1); },
+        },
+    };
+    let stdf_dir = config.exporters.stdf_dir.as_deref().map(Path::new);
+    if let Some(dir) = stdf_dir {
+        match stdf::StdfWriter::new(dir, config.exporters.stdf_lot.clone()) {
+            Ok(w) => history.set_stdf(w),
+            Err(e) => { eprintln!("Unable to create STDF directory {}: {}", dir.display(), e); std::process::exit(1); },
+        }
+    }
+    let history = Arc::new(Mutex::new(history));
+    let events = Arc::new(Mutex::new(EventBus::new()));
+    let metrics = Arc::new(Mutex::new(metrics::Metrics::new()));
+    let mut mnt = metrics::CountedMount::new(metrics.clone());
+
+    // Start the simulator before anything is sent, so that none of it goes
+    // to stdout instead.
+    let simulator = if matches.is_present("SIMULATE") {
+        eprintln!("Simulating a jig, with seed {}", simulator_options.seed);
+        Some(io::BufReader::new(simulator::start(simulator_options)))
+    } else {
+        None
+    };
 
-    cfti_send(OutgoingMessage::Log("HTTP interface starting up".to_string()));
+    if transport.is_stdio() && replay.is_none() {
+        cfti_send(OutgoingMessage::Log("HTTP interface starting up".to_string()));
+    }
 
     mnt.mount("/", staticfile);
 
@@ -573,29 +1214,172 @@ fn main() {
     let tmp_state = state.clone();
     mnt.mount("/stdin.txt", move |request: &mut Request| show_stdin(request, &tmp_state));
 
+    let tmp_logs = logs.clone();
+    mnt.mount("/log/current.json", move |request: &mut Request| logs::show_logs(request, &LogStore::view(&tmp_logs, LogView::CurrentRun)));
+
+    let tmp_logs = logs.clone();
+    mnt.mount("/log/previous.json", move |request: &mut Request| logs::show_logs(request, &LogStore::view(&tmp_logs, LogView::PreviousRun)));
+
     let tmp_state = state.clone();
-    mnt.mount("/log/current.json", move |request: &mut Request| show_current_logs_json(request, &tmp_state));
+    mnt.mount("/protocol/errors.json", move |request: &mut Request| show_protocol_errors_json(request, &tmp_state));
+
+    mnt.mount("/audit.json", audit::show_audit);
+
+    mnt.mount("/protocol/trace", trace::show_trace);
 
     let tmp_state = state.clone();
-    mnt.mount("/log/previous.json", move |request: &mut Request| show_previous_logs_json(request, &tmp_state));
+    let tmp_metrics = metrics.clone();
+    let tmp_logs = logs.clone();
+    mnt.mount("/metrics", move |request: &mut Request| metrics::show_metrics(request, &tmp_metrics, &tmp_state, &tmp_logs));
+
+    let tmp_events = events.clone();
+    mnt.mount("/events", move |request: &mut Request| events::show_events(request, &tmp_events));
 
     let tmp_state = state.clone();
-    mnt.mount("/start", move |request: &mut Request| start_tests(request, &tmp_state));
+    let tmp_history = history.clone();
+    mnt.mount("/timeline.json", move |request: &mut Request| timeline::show_timeline_json(request, &tmp_state, &tmp_history));
+
+    for &(path, format) in &[("/report.xml", report::ReportFormat::JUnit),
+                             ("/report.tap", report::ReportFormat::Tap),
+                             ("/report.csv", report::ReportFormat::Csv)] {
+        let tmp_state = state.clone();
+        let tmp_logs = logs.clone();
+        let tmp_history = history.clone();
+        mnt.mount(path, move |request: &mut Request| report::show_report(request, format, &tmp_state, &tmp_logs, &tmp_history));
+    }
 
-    let tmp_logs = logs.clone();
-    mnt.mount("/log.json", move |request: &mut Request| show_logs_json(request, &tmp_logs));
+    let tmp_history = history.clone();
+    mnt.mount("/runs.json", move |request: &mut Request| history::show_runs_json(request, &tmp_history));
+
+    let tmp_history = history.clone();
+    mnt.mount("/runs/", move |request: &mut Request| history::show_run_json(request, &tmp_history));
+
+    if let Some(dir) = stdf_dir {
+        mnt.mount("/stdf/", Static::new(dir));
+    }
 
     let tmp_logs = logs.clone();
-    mnt.mount("/truncate", move |request: &mut Request| truncate_logs(request, &tmp_logs));
+    mnt.mount("/log.json", move |request: &mut Request| logs::show_logs(request, &LogStore::view(&tmp_logs, LogView::All)));
 
-    mnt.mount("/exit", exit_server);
     mnt.mount("/hello", send_hello);
-    mnt.mount("/scenarios", send_scenarios);
-    mnt.mount("/scenario", select_scenario);
-    mnt.mount("/jig", get_jig);
-    mnt.mount("/tests", get_tests);
-    mnt.mount("/abort", abort_tests);
-
-    thread::spawn(move || stdin_monitor(state, logs));
-    Iron::new(mnt).http(format!("{}:{}", interface, port).as_str()).unwrap();
+    let tmp_events = events.clone();
+    mnt.mount("/scenarios", move |request: &mut Request| send_scenarios(request, &tmp_events));
+    let tmp_events = events.clone();
+    mnt.mount("/jig", move |request: &mut Request| get_jig(request, &tmp_events));
+    let tmp_events = events.clone();
+    mnt.mount("/tests", move |request: &mut Request| get_tests(request, &tmp_events));
+
+    let tmp_state = state.clone();
+    let tmp_logs = logs.clone();
+    let tmp_events = events.clone();
+    let tmp_pattern = serial_pattern.clone();
+    mnt.mount("/api/v1/", move |request: &mut Request| api::handle(request, &tmp_state, &tmp_logs, &tmp_events, &tmp_pattern));
+
+    if config.server.legacy_get {
+        let tmp_state = state.clone();
+        let tmp_events = events.clone();
+        mnt.mount("/start", move |request: &mut Request| start_tests(request, &tmp_state, &tmp_events));
+        mnt.mount("/abort", abort_tests);
+        mnt.mount("/exit", exit_server);
+        mnt.mount("/scenario", select_scenario);
+
+        let tmp_logs = logs.clone();
+        mnt.mount("/truncate", move |request: &mut Request| truncate_logs(request, &tmp_logs));
+
+        let tmp_state = state.clone();
+        let tmp_events = events.clone();
+        mnt.mount("/identity", move |request: &mut Request| identity::set_identity(request, &tmp_state, &tmp_events, &serial_pattern));
+
+        let tmp_state = state.clone();
+        let tmp_events = events.clone();
+        mnt.mount("/identity/clear", move |request: &mut Request| identity::clear_identity(request, &tmp_state, &tmp_events));
+
+        mnt.mount("/protocol/trace/enable", trace::enable_trace);
+        mnt.mount("/protocol/trace/disable", trace::disable_trace);
+        mnt.mount("/protocol/trace/clear", trace::clear_trace);
+    } else {
+        for &(path, replacement) in &[("/start", "POST /api/v1/start"),
+                                      ("/abort", "POST /api/v1/abort"),
+                                      ("/exit", "POST /api/v1/exit"),
+                                      ("/scenario", "PUT /api/v1/scenario"),
+                                      ("/truncate", "DELETE /api/v1/logs"),
+                                      ("/identity", "PUT /api/v1/identity"),
+                                      ("/identity/clear", "DELETE /api/v1/identity"),
+                                      ("/protocol/trace/enable", "PUT /api/v1/trace"),
+                                      ("/protocol/trace/disable", "PUT /api/v1/trace"),
+                                      ("/protocol/trace/clear", "DELETE /api/v1/trace")] {
+            mnt.mount(path, move |request: &mut Request| api::legacy_disabled(request, path, replacement));
+        }
+    }
+
+    // The WebSocket server only speaks plain HTTP, so Config::check() keeps
+    // it from being a way around TLS.
+    if let Some(ws_port) = config.server.ws_port {
+        let ws_address = format!("{}:{}", config.server.address, ws_port);
+        let ws_server = match ws::bind(&ws_address) {
+            Ok(s) => s,
+            Err(e) => { eprintln!("Unable to listen for WebSocket connections on {}: {}", ws_address, e); std::process::exit(1); },
+        };
+        let tmp_state = state.clone();
+        let tmp_events = events.clone();
+        let tmp_auth = auth.clone();
+        thread::spawn(move || ws::serve(ws_server, tmp_state, tmp_events, tmp_auth));
+    }
+
+    // A replay can't answer, so there is nobody to introduce ourselves to.
+    if replay.is_none() {
+        let subscription = events.lock().unwrap().subscribe_internal();
+        let connected = transport.is_stdio();
+        thread::spawn(move || bootstrap::run(subscription, connected));
+    }
+
+    let mut monitor = Monitor {
+        state,
+        logs,
+        events,
+        history,
+        metrics,
+        policy: config.disconnect_policy(),
+        recorder,
+    };
+    match (simulator, replay) {
+        (Some(reader), _) => {
+            thread::spawn(move || stdin_monitor(monitor, reader, "the simulator"));
+        },
+        (None, _) if !transport.is_stdio() => {
+            thread::spawn(move || transport::run(transport, monitor));
+        },
+        (None, Some((path, replay_reader))) => {
+            monitor.recorder = None;
+            let reader = io::BufReader::new(replay_reader);
+            thread::spawn(move || stdin_monitor(monitor, reader, &format!("the replay of {}", path)));
+        },
+        (None, None) => {
+            // Stdin's lock can't be moved between threads, so take it in the new one.
+            thread::spawn(move || stdin_monitor(monitor, io::stdin().lock(), "stdin"));
+        },
+    }
+    let mut chain = Chain::new(mnt);
+    if let Some(auth) = auth {
+        chain.link_before(auth::Enforce(auth));
+    }
+    let address = format!("{}:{}", config.server.address, config.server.port);
+
+    // Every /events stream ties up a worker thread, so make room for them on
+    // top of the usual pool.
+    let mut server = Iron::new(chain);
+    server.threads += events::MAX_EVENT_STREAMS;
+    let listening = match tls {
+        None => server.http(address.as_str()),
+        Some(tls) => {
+            if let Err(e) = tls::reload_on_sighup(tls.clone()) {
+                eprintln!("Unable to watch for SIGHUP, so TLS certificates can't be reloaded: {}", e);
+            }
+            server.https(address.as_str(), tls)
+        },
+    };
+    if let Err(e) = listening {
+        eprintln!("Unable to listen on {}: {}", address, e);
+        std::process::exit(1);
+    }
 }
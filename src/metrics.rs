@@ -0,0 +1,235 @@
+use iron::prelude::*;
+use iron::status;
+use iron::mime::Mime;
+use iron::middleware::Handler;
+use mount::Mount;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use events::StateEvent;
+use logstore::LogStore;
+use super::{unix_time, InterfaceState, ScenarioState, TestResult};
+
+/// Upper bounds of the duration histogram buckets, in seconds.
+const DURATION_BUCKETS: [f64; 12] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// Number of observations that fell into each of DURATION_BUCKETS.
+    buckets: [u64; 12],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, &bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS.iter()) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn write(&self, out: &mut String, name: &str, label: &str, value: &str) {
+        for (bucket, bound) in self.buckets.iter().zip(DURATION_BUCKETS.iter()) {
+            writeln!(out, "{}_bucket{{{}=\"{}\",le=\"{}\"}} {}", name, label, escape(value), bound, bucket).unwrap();
+        }
+        writeln!(out, "{}_bucket{{{}=\"{}\",le=\"+Inf\"}} {}", name, label, escape(value), self.count).unwrap();
+        writeln!(out, "{}_sum{{{}=\"{}\"}} {}", name, label, escape(value), self.sum).unwrap();
+        writeln!(out, "{}_count{{{}=\"{}\"}} {}", name, label, escape(value), self.count).unwrap();
+    }
+}
+
+/// Counters gathered from the changes made by exclave's messages, and from HTTP requests.
+#[derive(Default)]
+pub struct Metrics {
+    runs_started: BTreeMap<String, u64>,
+    runs_finished: BTreeMap<(String, &'static str), u64>,
+    test_results: BTreeMap<(String, &'static str), u64>,
+    scenario_durations: BTreeMap<String, Histogram>,
+    test_durations: BTreeMap<String, Histogram>,
+    log_messages: BTreeMap<String, u64>,
+    http_requests: BTreeMap<(&'static str, u16), u64>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Count a change made by one of exclave's messages.
+    pub fn observe(&mut self, event: &StateEvent) {
+        match event {
+            StateEvent::Start { scenario } => {
+                *self.runs_started.entry(scenario.clone()).or_insert(0) += 1;
+            },
+            StateEvent::Finish { scenario, scenario_state, duration, .. } => {
+                let result = match scenario_state {
+                    ScenarioState::Pass => "pass",
+                    _ => "fail",
+                };
+                *self.runs_finished.entry((scenario.clone(), result)).or_insert(0) += 1;
+                if let Some(duration) = duration {
+                    self.scenario_durations.entry(scenario.clone()).or_default().observe(*duration);
+                }
+            },
+            StateEvent::TestResult { test, result, timing } => {
+                let result = match result {
+                    TestResult::Pass(_) => "pass",
+                    TestResult::Fail(_) => "fail",
+                    TestResult::Skipped(_) => "skip",
+                    TestResult::Pending | TestResult::Running => return,
+                };
+                *self.test_results.entry((test.clone(), result)).or_insert(0) += 1;
+                if let Some(duration) = timing.duration {
+                    self.test_durations.entry(test.clone()).or_default().observe(duration);
+                }
+            },
+            StateEvent::Log { log } => {
+                *self.log_messages.entry(log.message_class.clone()).or_insert(0) += 1;
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Escape a label value for the text exposition format.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// A Mount that counts the requests made to each of its routes.
+pub struct CountedMount {
+    mount: Mount,
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+/// Counts the requests handled by a single route.
+struct Counted<H> {
+    route: &'static str,
+    metrics: Arc<Mutex<Metrics>>,
+    handler: H,
+}
+
+impl<H: Handler> Handler for Counted<H> {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let result = self.handler.handle(request);
+        let code = match result {
+            Ok(ref response) => response.status,
+            Err(ref e) => e.response.status,
+        };
+        let code = code.map(|c| c.to_u16()).unwrap_or(0);
+        *self.metrics.lock().unwrap().http_requests.entry((self.route, code)).or_insert(0) += 1;
+        result
+    }
+}
+
+impl CountedMount {
+    pub fn new(metrics: Arc<Mutex<Metrics>>) -> CountedMount {
+        CountedMount {
+            mount: Mount::new(),
+            metrics,
+        }
+    }
+
+    pub fn mount<H: Handler>(&mut self, route: &'static str, handler: H) -> &mut CountedMount {
+        let counted = Counted {
+            route,
+            metrics: self.metrics.clone(),
+            handler,
+        };
+        self.mount.mount(route, counted);
+        self
+    }
+}
+
+impl Handler for CountedMount {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        self.mount.handle(request)
+    }
+}
+
+/// Serve /metrics in the Prometheus text exposition format.
+pub fn show_metrics(_: &mut Request,
+                    metrics: &Arc<Mutex<Metrics>>,
+                    state: &Arc<Mutex<InterfaceState>>,
+                    logs: &Arc<Mutex<LogStore>>) -> IronResult<Response> {
+    let (protocol_errors, last_line) = {
+        let state = state.lock().unwrap();
+        (state.protocol_errors.count(), state.last_line)
+    };
+    let ((memory_count, memory_bytes), (spill_count, spill_bytes)) = {
+        let logs = logs.lock().unwrap();
+        (logs.memory_usage(), logs.spill_usage())
+    };
+    let metrics = metrics.lock().unwrap();
+    let mut out = String::new();
+
+    write_header(&mut out, "jig_runs_started_total", "counter", "Scenarios started, by scenario.");
+    for (scenario, count) in &metrics.runs_started {
+        writeln!(out, "jig_runs_started_total{{scenario=\"{}\"}} {}", escape(scenario), count).unwrap();
+    }
+
+    write_header(&mut out, "jig_runs_finished_total", "counter", "Scenarios finished, by scenario and result.");
+    for ((scenario, result), count) in &metrics.runs_finished {
+        writeln!(out, "jig_runs_finished_total{{scenario=\"{}\",result=\"{}\"}} {}", escape(scenario), result, count).unwrap();
+    }
+
+    write_header(&mut out, "jig_test_results_total", "counter", "Tests that passed, failed, or were skipped, by test.");
+    for ((test, result), count) in &metrics.test_results {
+        writeln!(out, "jig_test_results_total{{test=\"{}\",result=\"{}\"}} {}", escape(test), result, count).unwrap();
+    }
+
+    write_header(&mut out, "jig_scenario_duration_seconds", "histogram", "Time from START to FINISH, by scenario.");
+    for (scenario, histogram) in &metrics.scenario_durations {
+        histogram.write(&mut out, "jig_scenario_duration_seconds", "scenario", scenario);
+    }
+
+    write_header(&mut out, "jig_test_duration_seconds", "histogram", "Time from RUNNING to a test's result, by test.");
+    for (test, histogram) in &metrics.test_durations {
+        histogram.write(&mut out, "jig_test_duration_seconds", "test", test);
+    }
+
+    write_header(&mut out, "jig_log_messages_total", "counter", "Log messages received, by message class.");
+    for (class, count) in &metrics.log_messages {
+        writeln!(out, "jig_log_messages_total{{class=\"{}\"}} {}", escape(class), count).unwrap();
+    }
+
+    write_header(&mut out, "jig_protocol_errors_total", "counter", "Lines from exclave that could not be understood.");
+    writeln!(out, "jig_protocol_errors_total {}", protocol_errors).unwrap();
+
+    // Left out entirely until the first line arrives, rather than reporting a made-up age.
+    if let Some(last_line) = last_line {
+        let age = unix_time().checked_sub(last_line).unwrap_or_default();
+        write_header(&mut out, "jig_seconds_since_last_line", "gauge", "Time since the last line was received from exclave.");
+        writeln!(out, "jig_seconds_since_last_line {}", age.as_secs_f64()).unwrap();
+    }
+
+    write_header(&mut out, "jig_log_memory_messages", "gauge", "Log messages held in memory.");
+    writeln!(out, "jig_log_memory_messages {}", memory_count).unwrap();
+    write_header(&mut out, "jig_log_memory_bytes", "gauge", "Approximate memory used by log messages.");
+    writeln!(out, "jig_log_memory_bytes {}", memory_bytes).unwrap();
+    write_header(&mut out, "jig_log_spilled_messages", "gauge", "Log messages moved out of memory into spill files.");
+    writeln!(out, "jig_log_spilled_messages {}", spill_count).unwrap();
+    write_header(&mut out, "jig_log_spilled_bytes", "gauge", "Size of the log spill files.");
+    writeln!(out, "jig_log_spilled_bytes {}", spill_bytes).unwrap();
+
+    write_header(&mut out, "jig_http_requests_total", "counter", "HTTP requests handled, by route and status code.");
+    for ((route, code), count) in &metrics.http_requests {
+        writeln!(out, "jig_http_requests_total{{route=\"{}\",code=\"{}\"}} {}", escape(route), code, count).unwrap();
+    }
+
+    let content_type = "text/plain; version=0.0.4".parse::<Mime>().unwrap();
+    Ok(Response::with((content_type, status::Ok, out)))
+}
@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, LineWriter, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::unix_time;
+
+/// Writes each line received from the server to a file, along with when it
+/// arrived.  Each line of the file is the number of seconds since recording
+/// started, a tab, and the line itself.  Lines starting with '#' are comments.
+pub struct Recorder {
+    file: LineWriter<File>,
+    started: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Recorder> {
+        let mut file = LineWriter::new(File::create(path)?);
+        writeln!(file, "# CFTI session recorded at Unix time {}", unix_time().as_secs())?;
+        Ok(Recorder {
+            file,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, line: &str) {
+        let offset = self.started.elapsed().as_secs_f64();
+        let line = line.trim_end_matches(['\r', '\n']);
+        if let Err(e) = writeln!(self.file, "{:.6}\t{}", offset, line) {
+            eprintln!("Unable to record line {:?}: {}", line, e);
+        }
+    }
+}
+
+/// Plays back a file made by Recorder as though the lines were arriving from
+/// the server, at the same pace they originally did.
+pub struct ReplayReader {
+    lines: io::Lines<BufReader<File>>,
+    started: Instant,
+
+    /// How many times faster than real time to play back, or 0 to not wait at all.
+    speed: f64,
+
+    /// The rest of the line currently being read.
+    pending: Vec<u8>,
+}
+
+impl ReplayReader {
+    pub fn open(path: &Path, speed: f64) -> io::Result<ReplayReader> {
+        Ok(ReplayReader {
+            lines: BufReader::new(File::open(path)?).lines(),
+            started: Instant::now(),
+            speed,
+            pending: vec![],
+        })
+    }
+
+    /// Wait until the next recorded line is due, and return it.
+    fn next_line(&mut self) -> io::Result<Option<String>> {
+        for line in &mut self.lines {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (offset, text) = match line.split_once('\t') {
+                Some(parts) => parts,
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Recorded line has no timestamp: {:?}", line))),
+            };
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid timestamp in recorded line: {:?}", line));
+            let offset = match offset.parse::<f64>() {
+                Ok(o) if o >= 0.0 && o.is_finite() => o,
+                _ => return Err(invalid()),
+            };
+
+            if self.speed > 0.0 {
+                // Offsets too far in the future to wait for are as good as invalid.
+                let due = Duration::try_from_secs_f64(offset / self.speed).ok()
+                                                                          .and_then(|d| self.started.checked_add(d))
+                                                                          .ok_or_else(invalid)?;
+                if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+            }
+            return Ok(Some(text.to_string()));
+        }
+        Ok(None)
+    }
+}
+
+impl Read for ReplayReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.next_line()? {
+                None => return Ok(0),
+                Some(line) => {
+                    self.pending = line.into_bytes();
+                    self.pending.push(b'\n');
+                },
+            }
+        }
+        let count = buf.len().min(self.pending.len());
+        buf[..count].copy_from_slice(&self.pending[..count]);
+        self.pending.drain(..count);
+        Ok(count)
+    }
+}
@@ -0,0 +1,338 @@
+use iron::prelude::*;
+use iron::status;
+use iron::mime::Mime;
+use urlencoded;
+
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use history::{RunHistory, RunSummary, RunTest};
+use identity::DutIdentity;
+use logs::LogSource;
+use logstore::{LogStore, LogView};
+use super::{InterfaceState, LogMessage, TestResult};
+
+/// The formats a run can be exported in.
+#[derive(Clone, Copy, Debug)]
+pub enum ReportFormat {
+    /// JUnit XML, as understood by most CI systems.
+    JUnit,
+
+    /// Test Anything Protocol, version 13.
+    Tap,
+
+    /// One row per test, with a header line.
+    Csv,
+}
+
+impl ReportFormat {
+    fn content_type(&self) -> &'static str {
+        match *self {
+            ReportFormat::JUnit => "application/xml",
+            ReportFormat::Tap => "text/plain",
+            ReportFormat::Csv => "text/csv",
+        }
+    }
+
+    fn render(&self, run: &RunSummary, logs: &dyn LogSource) -> String {
+        match *self {
+            ReportFormat::JUnit => junit_report(run, logs),
+            ReportFormat::Tap => tap_report(run, logs),
+            ReportFormat::Csv => csv_report(run, logs),
+        }
+    }
+}
+
+fn seconds(d: time::Duration) -> String {
+    format!("{}.{:03}", d.as_secs(), d.subsec_millis())
+}
+
+fn format_log(log: &LogMessage) -> String {
+    format!("{} {} {} {}: {}", seconds(log.timestamp), log.message_class, log.unit_type, log.unit_id, log.message)
+}
+
+/// The log messages that were generated by the given test, each formatted
+/// with `format` and separated by newlines.
+fn test_logs(logs: &dyn LogSource, test: &RunTest, format: fn(&LogMessage) -> String) -> String {
+    let mut out = String::new();
+    logs.scan(None, &mut |log| {
+        if log.unit_id == test.id {
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format(log));
+        }
+        true
+    });
+    out
+}
+
+/// The name to report a test under, falling back to its ID if it was never DESCRIBEd.
+fn test_name(test: &RunTest) -> &str {
+    if test.name.is_empty() { &test.id } else { &test.name }
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // Control characters other than whitespace are not allowed in XML at all.
+            c if c.is_control() && c != '\n' && c != '\r' && c != '\t' => (),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Quote `s` as a YAML double-quoted scalar.  Rust's {:?} looks much the
+/// same, but its escapes, such as "\u{1b}", aren't YAML.
+fn yaml_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            // Everything else that YAML doesn't allow in a document, or
+            // would read as a line break.
+            c if c.is_control() || matches!(c, '\u{2028}' | '\u{2029}' | '\u{feff}' | '\u{fffe}' | '\u{ffff}') => {
+                write!(out, "\\u{:04x}", c as u32).unwrap();
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn junit_report(run: &RunSummary, logs: &dyn LogSource) -> String {
+    let count = |f: fn(&TestResult) -> bool| run.tests.iter().filter(|t| f(&t.result)).count();
+    let failures = count(|r| matches!(r, TestResult::Fail(_)));
+    let skipped = count(|r| !matches!(r, TestResult::Pass(_) | TestResult::Fail(_)));
+
+    let mut out = String::new();
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>").unwrap();
+    writeln!(out, "<testsuites>").unwrap();
+    write!(out, "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\" skipped=\"{}\"",
+           xml_escape(&run.scenario), run.tests.len(), failures, skipped).unwrap();
+    if let Some(duration) = run.duration {
+        write!(out, " time=\"{}\"", seconds(duration)).unwrap();
+    }
+    writeln!(out, ">").unwrap();
+
+    writeln!(out, "    <properties>").unwrap();
+    writeln!(out, "      <property name=\"run\" value=\"{}\"/>", run.id).unwrap();
+    writeln!(out, "      <property name=\"jig\" value=\"{}\"/>", xml_escape(&run.jig)).unwrap();
+    if let Some(ref station) = run.station.name {
+        writeln!(out, "      <property name=\"station\" value=\"{}\"/>", xml_escape(station)).unwrap();
+    }
+    writeln!(out, "      <property name=\"started\" value=\"{}\"/>", seconds(run.started)).unwrap();
+    if let Some(result) = run.result {
+        writeln!(out, "      <property name=\"result\" value=\"{}\"/>", result).unwrap();
+    }
+    if let Some(ref identity) = run.identity {
+        writeln!(out, "      <property name=\"serial\" value=\"{}\"/>", xml_escape(&identity.serial)).unwrap();
+        for (name, value) in identity.extra_fields() {
+            writeln!(out, "      <property name=\"{}\" value=\"{}\"/>", name, xml_escape(value)).unwrap();
+        }
+    }
+    writeln!(out, "    </properties>").unwrap();
+
+    for test in &run.tests {
+        write!(out, "    <testcase classname=\"{}\" name=\"{}\"",
+               xml_escape(&run.scenario), xml_escape(test_name(test))).unwrap();
+        if let Some(duration) = test.timing.duration {
+            write!(out, " time=\"{}\"", seconds(duration)).unwrap();
+        }
+        writeln!(out, ">").unwrap();
+
+        match test.result {
+            TestResult::Pass(_) => (),
+            TestResult::Fail(ref reason) => writeln!(out, "      <failure message=\"{}\"/>", xml_escape(reason)).unwrap(),
+            TestResult::Skipped(ref reason) => writeln!(out, "      <skipped message=\"{}\"/>", xml_escape(reason)).unwrap(),
+            TestResult::Pending => writeln!(out, "      <skipped message=\"Not run\"/>").unwrap(),
+            TestResult::Running => writeln!(out, "      <skipped message=\"Did not finish\"/>").unwrap(),
+        }
+
+        let test_out = test_logs(logs, test, |log| xml_escape(&format_log(log)));
+        if !test_out.is_empty() {
+            writeln!(out, "      <system-out>{}</system-out>", test_out).unwrap();
+        }
+        writeln!(out, "    </testcase>").unwrap();
+    }
+
+    write!(out, "    <system-out>").unwrap();
+    let mut separator = "";
+    logs.scan(None, &mut |log| {
+        write!(out, "{}{}", separator, xml_escape(&format_log(log))).unwrap();
+        separator = "\n";
+        true
+    });
+    writeln!(out, "</system-out>").unwrap();
+    writeln!(out, "  </testsuite>").unwrap();
+    writeln!(out, "</testsuites>").unwrap();
+    out
+}
+
+fn tap_report(run: &RunSummary, logs: &dyn LogSource) -> String {
+    // TAP descriptions end at a "#", and each line stands on its own.
+    let clean = |s: &str| s.replace('#', "\\#").replace('\n', " ");
+
+    let mut out = String::new();
+    writeln!(out, "TAP version 13").unwrap();
+    if let Some(ref identity) = run.identity {
+        writeln!(out, "# serial: {}", clean(&identity.serial)).unwrap();
+        for (name, value) in identity.extra_fields() {
+            writeln!(out, "# {}: {}", name, clean(value)).unwrap();
+        }
+    }
+    writeln!(out, "1..{}", run.tests.len()).unwrap();
+    for (number, test) in run.tests.iter().enumerate() {
+        let number = number + 1;
+        let name = clean(test_name(test));
+        match test.result {
+            TestResult::Pass(_) => writeln!(out, "ok {} - {}", number, name),
+            TestResult::Fail(_) => writeln!(out, "not ok {} - {}", number, name),
+            TestResult::Skipped(ref reason) => writeln!(out, "ok {} - {} # SKIP {}", number, name, clean(reason)),
+            TestResult::Pending => writeln!(out, "not ok {} - {} # TODO not run", number, name),
+            TestResult::Running => writeln!(out, "not ok {} - {} # TODO did not finish", number, name),
+        }.unwrap();
+
+        // Extra detail goes in a YAML block beneath the test line.
+        let message = match test.result {
+            TestResult::Pass(ref m) | TestResult::Fail(ref m) => m.as_str(),
+            _ => "",
+        };
+        let test_out = test_logs(logs, test, |log| format!("    - {}", yaml_string(&format_log(log))));
+        if message.is_empty() && test.timing.duration.is_none() && test_out.is_empty() {
+            continue;
+        }
+        writeln!(out, "  ---").unwrap();
+        if !message.is_empty() {
+            writeln!(out, "  message: {}", yaml_string(message)).unwrap();
+        }
+        if let Some(duration) = test.timing.duration {
+            writeln!(out, "  duration_ms: {}", duration.as_millis()).unwrap();
+        }
+        if !test_out.is_empty() {
+            writeln!(out, "  logs:").unwrap();
+            writeln!(out, "{}", test_out).unwrap();
+        }
+        writeln!(out, "  ...").unwrap();
+    }
+    out
+}
+
+fn csv_report(run: &RunSummary, logs: &dyn LogSource) -> String {
+    let identity = |f: fn(&DutIdentity) -> Option<String>| run.identity.as_ref().and_then(f).unwrap_or_default();
+    let mut out = String::new();
+    out.push_str("run,jig,scenario,serial,lot,work_order,operator,test_id,test_name,test_description,result,message,started,finished,duration,logs\r\n");
+    for test in &run.tests {
+        let (result, message) = match test.result {
+            TestResult::Pending => ("pending", ""),
+            TestResult::Running => ("running", ""),
+            TestResult::Pass(ref m) => ("pass", m.as_str()),
+            TestResult::Fail(ref m) => ("fail", m.as_str()),
+            TestResult::Skipped(ref m) => ("skipped", m.as_str()),
+        };
+        let fields = [
+            run.id.to_string(),
+            run.jig.clone(),
+            run.scenario.clone(),
+            identity(|i| Some(i.serial.clone())),
+            identity(|i| i.lot.clone()),
+            identity(|i| i.work_order.clone()),
+            identity(|i| i.operator.clone()),
+            test.id.clone(),
+            test.name.clone(),
+            test.description.clone(),
+            result.to_string(),
+            message.to_string(),
+            test.timing.started.map(seconds).unwrap_or_default(),
+            test.timing.finished.map(seconds).unwrap_or_default(),
+            test.timing.duration.map(seconds).unwrap_or_default(),
+            test_logs(logs, test, format_log),
+        ];
+        let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        out.push_str(&fields.join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+/// Serve /report.xml, /report.tap, or /report.csv.  The run is chosen with
+/// "run", which is "current" (the default), "previous", or a run ID.
+pub fn show_report(request: &mut Request,
+                   format: ReportFormat,
+                   state: &Arc<Mutex<InterfaceState>>,
+                   logs: &Arc<Mutex<LogStore>>,
+                   history: &Arc<Mutex<RunHistory>>) -> IronResult<Response> {
+    let which = match request.get_ref::<urlencoded::UrlEncodedQuery>() {
+        Ok(query) => query.get("run").map(|s| s[0].clone()),
+        Err(_) => None,
+    };
+    let which = which.unwrap_or_else(|| "current".to_string());
+
+    // The current run's logs are read straight from the log store, rather
+    // than copied out of it first.
+    let body = match which.as_str() {
+        "current" => {
+            let run = {
+                let data = state.lock().unwrap();
+                data.run.as_ref().map(|run| RunSummary::from_state(&data, run))
+            };
+            run.map(|run| format.render(&run, &LogStore::view(logs, LogView::CurrentRun)))
+        },
+        "previous" => {
+            let current = state.lock().unwrap().run.as_ref().map(|run| run.id);
+            let record = history.lock().unwrap().previous(current);
+            record.map(|record| format.render(&record.run, &record.logs))
+        },
+        id => match id.parse() {
+            Ok(id) => {
+                let record = history.lock().unwrap().get(id);
+                record.map(|record| format.render(&record.run, &record.logs))
+            },
+            Err(_) => return Ok(Response::with((status::BadRequest, format!("Unable to parse run value: {:?}", id)))),
+        },
+    };
+    let body = match body {
+        Some(b) => b,
+        None => return Ok(Response::with((status::NotFound, format!("No such run: {}", which)))),
+    };
+
+    let content_type = format.content_type().parse::<Mime>().unwrap();
+    Ok(Response::with((content_type, status::Ok, body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaml_string_uses_yaml_escapes() {
+        assert_eq!(yaml_string("plain"), "\"plain\"");
+        assert_eq!(yaml_string("say \"hi\" \\ bye"), "\"say \\\"hi\\\" \\\\ bye\"");
+        assert_eq!(yaml_string("two\nlines\ttab"), "\"two\\nlines\\ttab\"");
+        assert_eq!(yaml_string("\u{1b}[31mred\u{7f}"), "\"\\u001b[31mred\\u007f\"");
+        assert_eq!(yaml_string("a\u{2028}b"), "\"a\\u2028b\"");
+        assert_eq!(yaml_string("µs → ok"), "\"µs → ok\"");
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{unix_time, OutgoingMessage};
+
+/// Where cfti_send delivers messages while the simulator stands in for the server.
+static SIMULATOR: Mutex<Option<Sender<OutgoingMessage>>> = Mutex::new(None);
+
+const JIG: (&str, &str, &str) = ("netv2-sim", "Simulated NeTV2 Jig", "A pretend jig, for trying out the web interface without any hardware");
+
+/// Each test's id, name, description, what it measures when it passes,
+/// and what goes wrong when it fails.
+const TESTS: [(&str, &str, &str, &str, &str); 7] = [
+    ("power", "Power Rails", "Check that every supply rail is within 5% of nominal", "3.31 V", "3V3 rail out of range: 2.87 V"),
+    ("fpga", "FPGA Configuration", "Load a bitstream and read back the device DNA", "Loaded in 412 ms", "DONE pin never went high"),
+    ("ddr", "DDR Memory", "Write and verify a pattern across all of DDR3", "512 MiB verified", "Mismatch at 0x0c3a1f40"),
+    ("hdmi-in", "HDMI Input", "Lock onto a 1080p test pattern", "1920x1080 at 60 Hz", "No TMDS clock detected"),
+    ("hdmi-out", "HDMI Output", "Loop the output back and compare frames", "0 pixel errors", "148 pixel errors"),
+    ("pcie", "PCIe Link", "Train the PCIe link and check its width", "Gen2 x4", "Link trained at x1"),
+    ("leds", "Status LEDs", "Blink each LED and check it with the light sensor", "4 LEDs lit", "LED2 not detected"),
+];
+
+/// Each scenario's id, name, description, and tests.
+const SCENARIOS: [(&str, &str, &str, &[&str]); 3] = [
+    ("full", "Full Test", "Every test, as run on the production line", &["power", "fpga", "ddr", "hdmi-in", "hdmi-out", "pcie", "leds"]),
+    ("quick", "Quick Check", "Just enough to tell whether the board boots", &["power", "fpga", "leds"]),
+    ("video", "Video Path", "HDMI input and output only", &["power", "fpga", "hdmi-in", "hdmi-out"]),
+];
+
+/// How the simulated tests behave.
+#[derive(Clone, Debug)]
+pub struct SimulatorOptions {
+    /// Chance of each test failing, from 0 to 1.
+    pub fail_rate: f64,
+
+    /// Chance of each test being skipped, from 0 to 1.
+    pub skip_rate: f64,
+
+    /// Average time each test takes.  Each one takes between half and one
+    /// and a half times this long.
+    pub test_duration: Duration,
+
+    /// Seed for the random number generator, so that runs can be repeated.
+    pub seed: u64,
+}
+
+/// A small xorshift generator, which is plenty for picking test outcomes.
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        // Xorshift gets stuck at zero.
+        Random(seed.max(1))
+    }
+
+    /// A number from 0 up to, but not including, 1.
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+enum Step {
+    /// A line to send as it is.
+    Line(String),
+
+    /// A log message about a test, which is timestamped when it's sent.
+    Log(String, String),
+}
+
+/// Something the simulated server will say once `due` comes round.
+struct Scheduled {
+    due: Instant,
+    step: Step,
+
+    /// The test that is running once this has been sent, if any.
+    running: Option<String>,
+}
+
+struct Simulator {
+    options: SimulatorOptions,
+    random: Random,
+    lines: Sender<String>,
+    scenario: String,
+
+    /// What's left of the current run.  Empty if nothing is running.
+    pending: VecDeque<Scheduled>,
+    running: Option<String>,
+}
+
+fn tests_for(scenario: &str) -> Option<&'static [&'static str]> {
+    SCENARIOS.iter().find(|s| s.0 == scenario).map(|s| s.3)
+}
+
+impl Simulator {
+    /// Send a line as though it came from the server.
+    fn say(&self, line: String) {
+        // If nobody is listening any more, there's nothing to do about it.
+        let _ = self.lines.send(line);
+    }
+
+    fn say_jig(&self) {
+        self.say(format!("JIG {}", JIG.0));
+        self.say(format!("DESCRIBE JIG NAME {} {}", JIG.0, JIG.1));
+        self.say(format!("DESCRIBE JIG DESCRIPTION {} {}", JIG.0, JIG.2));
+    }
+
+    fn say_scenarios(&self) {
+        let ids: Vec<&str> = SCENARIOS.iter().map(|s| s.0).collect();
+        self.say(format!("SCENARIOS {}", ids.join(" ")));
+        for &(id, name, description, _) in &SCENARIOS {
+            self.say(format!("DESCRIBE SCENARIO NAME {} {}", id, name));
+            self.say(format!("DESCRIBE SCENARIO DESCRIPTION {} {}", id, description));
+        }
+    }
+
+    fn say_tests(&self) {
+        let tests = tests_for(&self.scenario).unwrap_or(&[]);
+        self.say(format!("TESTS {} {}", self.scenario, tests.join(" ")));
+        for &(id, name, description, _, _) in TESTS.iter().filter(|t| tests.contains(&t.0)) {
+            self.say(format!("DESCRIBE TEST NAME {} {}", id, name));
+            self.say(format!("DESCRIBE TEST DESCRIPTION {} {}", id, description));
+        }
+    }
+
+    fn log(&self, unit: &str, message: &str) {
+        let now = unix_time();
+        self.say(format!("LOG info {} test {} {} {}", unit, now.as_secs(), now.subsec_nanos(), message));
+    }
+
+    /// Plan out a run of `scenario`, with a random outcome for each test.
+    fn start(&mut self, scenario: String) {
+        if !self.pending.is_empty() {
+            self.log("simulator", "Ignoring START, since a scenario is already running");
+            return;
+        }
+        let tests = match tests_for(&scenario) {
+            Some(t) => t,
+            None => {
+                self.log("simulator", &format!("Unknown scenario {}", scenario));
+                return;
+            },
+        };
+
+        self.say(format!("START {}", scenario));
+        let mut due = Instant::now();
+        let mut failed = false;
+        for &(id, name, _, measurement, failure) in TESTS.iter().filter(|t| tests.contains(&t.0)) {
+            let roll = self.random.next();
+            if roll < self.options.skip_rate {
+                self.pending.push_back(Scheduled { due, step: Step::Line(format!("SKIP {} Not supported on this board revision", id)), running: None });
+                continue;
+            }
+
+            let duration = self.options.test_duration.mul_f64(0.5 + self.random.next());
+            let running = Some(id.to_string());
+            self.pending.push_back(Scheduled { due, step: Step::Line(format!("RUNNING {}", id)), running: running.clone() });
+            self.pending.push_back(Scheduled { due, step: Step::Log(id.to_string(), format!("Starting {}", name)), running: running.clone() });
+            self.pending.push_back(Scheduled { due: due + duration / 2, step: Step::Log(id.to_string(), format!("{}: halfway there", name)), running: running.clone() });
+
+            due += duration;
+            let result = if roll < self.options.skip_rate + self.options.fail_rate {
+                failed = true;
+                format!("FAIL {} {}", id, failure)
+            } else {
+                format!("PASS {} {}", id, measurement)
+            };
+            self.pending.push_back(Scheduled { due, step: Step::Line(result), running: None });
+        }
+
+        let finish = if failed { format!("FINISH {} 500 One or more tests failed", scenario) } else { format!("FINISH {} 200 All tests passed", scenario) };
+        self.pending.push_back(Scheduled { due, step: Step::Line(finish), running: None });
+        self.scenario = scenario;
+    }
+
+    fn abort(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        self.pending.clear();
+        if let Some(test) = self.running.take() {
+            self.say(format!("FAIL {} Aborted", test));
+        }
+        self.say(format!("FINISH {} 400 Aborted", self.scenario));
+    }
+
+    fn handle(&mut self, message: OutgoingMessage) {
+        match message {
+            OutgoingMessage::GetJig => self.say_jig(),
+            OutgoingMessage::Scenarios => self.say_scenarios(),
+            OutgoingMessage::GetTests => self.say_tests(),
+            OutgoingMessage::Scenario(scenario) => {
+                if !self.pending.is_empty() {
+                    self.log("simulator", "Can't change scenarios while one is running");
+                } else if tests_for(&scenario).is_none() {
+                    self.log("simulator", &format!("Unknown scenario {}", scenario));
+                } else {
+                    self.scenario = scenario;
+                    self.say(format!("SCENARIO {}", self.scenario));
+                    self.say_tests();
+                }
+            },
+            OutgoingMessage::StartTests(scenario) => {
+                let scenario = if scenario.is_empty() { self.scenario.clone() } else { scenario };
+                self.start(scenario);
+            },
+            OutgoingMessage::AbortTests => self.abort(),
+            OutgoingMessage::Hello(_) | OutgoingMessage::Log(_) | OutgoingMessage::Shutdown(_) | OutgoingMessage::Pong(_) => (),
+        }
+    }
+
+    /// Send everything in the current run that has come due.
+    fn advance(&mut self) {
+        let now = Instant::now();
+        while self.pending.front().is_some_and(|s| s.due <= now) {
+            let scheduled = self.pending.pop_front().unwrap();
+            match scheduled.step {
+                Step::Line(line) => self.say(line),
+                Step::Log(unit, message) => self.log(&unit, &message),
+            }
+            self.running = scheduled.running;
+        }
+    }
+
+    fn run(mut self, messages: Receiver<OutgoingMessage>) {
+        loop {
+            let message = match self.pending.front() {
+                None => messages.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                Some(next) => messages.recv_timeout(next.due.saturating_duration_since(Instant::now())),
+            };
+            match message {
+                Ok(message) => self.handle(message),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            self.advance();
+        }
+    }
+}
+
+/// Reads the lines the simulator says, for stdin_monitor.
+pub struct SimulatorReader {
+    lines: Receiver<String>,
+
+    /// The rest of the line currently being read.
+    pending: Vec<u8>,
+}
+
+impl Read for SimulatorReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.lines.recv() {
+                Err(_) => return Ok(0),
+                Ok(line) => {
+                    self.pending = line.into_bytes();
+                    self.pending.push(b'\n');
+                },
+            }
+        }
+        let count = buf.len().min(self.pending.len());
+        buf[..count].copy_from_slice(&self.pending[..count]);
+        self.pending.drain(..count);
+        Ok(count)
+    }
+}
+
+/// Start the simulator, and from now on send it every outgoing message
+/// instead of writing them to stdout.
+pub fn start(options: SimulatorOptions) -> SimulatorReader {
+    let (message_tx, message_rx) = channel();
+    let (line_tx, line_rx) = channel();
+    let simulator = Simulator {
+        random: Random::new(options.seed),
+        options,
+        lines: line_tx,
+        scenario: SCENARIOS[0].0.to_string(),
+        pending: VecDeque::new(),
+        running: None,
+    };
+
+    // Like exclave, introduce ourselves without being asked.
+    simulator.say("HELLO exclave simulator".to_string());
+    simulator.say(format!("SCENARIO {}", simulator.scenario));
+
+    *SIMULATOR.lock().unwrap() = Some(message_tx);
+    thread::spawn(move || simulator.run(message_rx));
+
+    SimulatorReader {
+        lines: line_rx,
+        pending: vec![],
+    }
+}
+
+/// Pass `message` to the simulator if it's running.  Returns false if it
+/// isn't, so that the message should go to the real server.
+pub fn deliver(message: &OutgoingMessage) -> bool {
+    match *SIMULATOR.lock().unwrap() {
+        Some(ref simulator) => simulator.send(message.clone()).is_ok(),
+        None => false,
+    }
+}
@@ -0,0 +1,329 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use history::{RunSummary, RunTest};
+use identity::DutIdentity;
+use super::{unix_time, ScenarioState, TestResult};
+
+/// Record types, as (REC_TYP, REC_SUB).
+const FAR: (u8, u8) = (0, 10);
+const MIR: (u8, u8) = (1, 10);
+const MRR: (u8, u8) = (1, 20);
+const PIR: (u8, u8) = (5, 10);
+const PRR: (u8, u8) = (5, 20);
+const PTR: (u8, u8) = (15, 10);
+const FTR: (u8, u8) = (15, 20);
+
+/// FAR CPU_TYPE for little-endian (i386) byte order, which everything below uses.
+const CPU_TYPE_LITTLE_ENDIAN: u8 = 2;
+const STDF_VERSION: u8 = 4;
+
+/// Every run is tested as a single part, on a single head and site.
+const HEAD_NUM: u8 = 1;
+const SITE_NUM: u8 = 1;
+
+/// TEST_FLG bits.
+const TEST_NOT_EXECUTED: u8 = 1 << 4;
+const TEST_NO_PASS_FAIL: u8 = 1 << 6;
+const TEST_FAILED: u8 = 1 << 7;
+
+/// PRR PART_FLG bits.
+const PART_FAILED: u8 = 1 << 3;
+const PART_NO_PASS_FAIL: u8 = 1 << 4;
+
+/// Bins assigned to good and bad parts.
+const BIN_PASS: u16 = 1;
+const BIN_FAIL: u16 = 2;
+
+/// Builds the body of a single record.  All fields are little-endian.
+struct Record {
+    kind: (u8, u8),
+    data: Vec<u8>,
+}
+
+impl Record {
+    fn new(kind: (u8, u8)) -> Record {
+        Record { kind, data: vec![] }
+    }
+
+    fn u1(mut self, v: u8) -> Record {
+        self.data.push(v);
+        self
+    }
+
+    fn u2(mut self, v: u16) -> Record {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u4(mut self, v: u32) -> Record {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn i1(self, v: i8) -> Record {
+        self.u1(v as u8)
+    }
+
+    fn i2(self, v: i16) -> Record {
+        self.u2(v as u16)
+    }
+
+    fn i4(self, v: i32) -> Record {
+        self.u4(v as u32)
+    }
+
+    fn r4(self, v: f32) -> Record {
+        self.u4(v.to_bits())
+    }
+
+    /// C*1: a single character.
+    fn c1(self, v: char) -> Record {
+        self.u1(if v.is_ascii() { v as u8 } else { b' ' })
+    }
+
+    /// C*n: a length byte followed by up to 255 characters.
+    fn cn(mut self, v: &str) -> Record {
+        let mut bytes = v.as_bytes();
+        if bytes.len() > 255 {
+            bytes = &bytes[..255];
+        }
+        self.data.push(bytes.len() as u8);
+        self.data.extend_from_slice(bytes);
+        self
+    }
+
+    fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        let len = self.data.len() as u16;
+        out.write_all(&len.to_le_bytes())?;
+        out.write_all(&[self.kind.0, self.kind.1])?;
+        out.write_all(&self.data)
+    }
+}
+
+/// STDF times are 32-bit Unix times.
+fn stdf_time(d: ::std::time::Duration) -> u32 {
+    d.as_secs() as u32
+}
+
+/// Pull a number, and optionally its units, from the start of a PASS or FAIL
+/// message, e.g. "3.31 V", "3.31V", or "47".
+fn measurement(message: &str) -> Option<(f32, String)> {
+    let mut words = message.split_whitespace();
+    let first = words.next()?;
+    if let Ok(value) = first.parse() {
+        let units = words.next().unwrap_or("").to_string();
+        return Some((value, units));
+    }
+
+    let number = first.trim_end_matches(|c: char| c.is_alphabetic() || c == '%');
+    if number.is_empty() || number.len() == first.len() {
+        return None;
+    }
+    let value = number.parse().ok()?;
+    Some((value, first[number.len()..].to_string()))
+}
+
+/// The name to record a test under, falling back to its ID if it was never DESCRIBEd.
+fn test_text(test: &RunTest) -> &str {
+    if test.name.is_empty() { &test.id } else { &test.name }
+}
+
+fn test_record(number: u32, test: &RunTest) -> Record {
+    let (flags, message) = match test.result {
+        TestResult::Pass(ref m) => (0, m.as_str()),
+        TestResult::Fail(ref m) => (TEST_FAILED, m.as_str()),
+        TestResult::Skipped(ref m) => (TEST_NOT_EXECUTED | TEST_NO_PASS_FAIL, m.as_str()),
+        TestResult::Pending | TestResult::Running => (TEST_NOT_EXECUTED | TEST_NO_PASS_FAIL, ""),
+    };
+
+    // Tests that reported a number become parametric tests; the rest are functional.
+    if flags & TEST_NOT_EXECUTED == 0 {
+        if let Some((value, units)) = measurement(message) {
+            return Record::new(PTR)
+                .u4(number).u1(HEAD_NUM).u1(SITE_NUM)
+                .u1(flags)
+                .u1(0)                  // PARM_FLG
+                .r4(value)
+                .cn(test_text(test))
+                .cn("")                 // ALARM_ID
+                .u1(0xfe)               // OPT_FLAG: no scaling, and no limits of any kind
+                .i1(0).i1(0).i1(0)      // RES_SCAL, LLM_SCAL, HLM_SCAL
+                .r4(0.0).r4(0.0)        // LO_LIMIT, HI_LIMIT
+                .cn(&units);
+        }
+    }
+
+    Record::new(FTR)
+        .u4(number).u1(HEAD_NUM).u1(SITE_NUM)
+        .u1(flags)
+        .u1(0xff)                       // OPT_FLAG: none of the optional data is valid
+        .u4(0).u4(0).u4(0).u4(0)        // CYCL_CNT, REL_VADR, REPT_CNT, NUM_FAIL
+        .i4(0).i4(0).i2(0)              // XFAIL_AD, YFAIL_AD, VECT_OFF
+        .u2(0).u2(0)                    // RTN_ICNT, PGM_ICNT
+        .u2(0)                          // FAIL_PIN, an empty bit field
+        .cn("").cn("").cn("")           // VECT_NAM, TIME_SET, OP_CODE
+        .cn(test_text(test))
+        .cn("").cn("")                  // ALARM_ID, PROG_TXT
+        .cn(message)                    // RSLT_TXT
+}
+
+/// The PIR, test records, and PRR for one run.
+fn part_records(run: &RunSummary) -> Vec<Record> {
+    let mut records = vec![Record::new(PIR).u1(HEAD_NUM).u1(SITE_NUM)];
+
+    for (index, test) in run.tests.iter().enumerate() {
+        records.push(test_record(index as u32 + 1, test));
+    }
+
+    let executed = run.tests.iter().filter(|t| matches!(t.result, TestResult::Pass(_) | TestResult::Fail(_))).count();
+    let (part_flags, hard_bin) = match run.scenario_state {
+        ScenarioState::Pass => (0, BIN_PASS),
+        ScenarioState::Fail => (PART_FAILED, BIN_FAIL),
+        ScenarioState::Pending | ScenarioState::Running => (PART_NO_PASS_FAIL, BIN_FAIL),
+    };
+    let part_id = match run.identity {
+        Some(ref identity) => identity.serial.clone(),
+        None => run.id.to_string(),
+    };
+    let test_time = run.duration.map(|d| d.as_millis() as u32).unwrap_or(0);
+    records.push(Record::new(PRR)
+        .u1(HEAD_NUM).u1(SITE_NUM)
+        .u1(part_flags)
+        .u2(executed as u16)
+        .u2(hard_bin)
+        .u2(run.result.map(|r| r.min(0xffff) as u16).unwrap_or(0xffff))   // SOFT_BIN is the FINISH result
+        .i2(i16::MIN).i2(i16::MIN)      // X_COORD, Y_COORD: not a wafer
+        .u4(test_time)
+        .cn(&part_id)                   // PART_ID
+        .cn(&run.scenario));            // PART_TXT
+    records
+}
+
+fn far_record() -> Record {
+    Record::new(FAR).u1(CPU_TYPE_LITTLE_ENDIAN).u1(STDF_VERSION)
+}
+
+fn mir_record(run: &RunSummary, lot: &str) -> Record {
+    let identity = |f: fn(&DutIdentity) -> Option<&str>| run.identity.as_ref().and_then(f).unwrap_or("");
+    Record::new(MIR)
+        .u4(stdf_time(run.started))     // SETUP_T
+        .u4(stdf_time(run.started))     // START_T
+        .u1(run.station.number.unwrap_or(1))    // STAT_NUM
+        .c1('P')                        // MODE_COD: production
+        .c1(' ').c1(' ')                // RTST_COD, PROT_COD
+        .u2(0xffff)                     // BURN_TIM
+        .c1(' ')                        // CMOD_COD
+        .cn(lot)
+        .cn(&run.scenario)              // PART_TYP
+        .cn(run.station.name.as_deref().unwrap_or(&run.jig))   // NODE_NAM
+        .cn(&run.jig_name)              // TSTR_TYP
+        .cn(&run.scenario)              // JOB_NAM
+        .cn("").cn("")                  // JOB_REV, SBLOT_ID
+        .cn(identity(|i| i.operator.as_deref()))    // OPER_NAM
+        .cn(env!("CARGO_PKG_NAME"))     // EXEC_TYP
+        .cn(env!("CARGO_PKG_VERSION"))  // EXEC_VER
+        .cn("").cn("")                  // TEST_COD, TST_TEMP
+        .cn(identity(|i| i.work_order.as_deref()))  // USER_TXT
+}
+
+fn mrr_record() -> Record {
+    Record::new(MRR).u4(stdf_time(unix_time())).c1(' ').cn("").cn("")
+}
+
+/// Turns a lot ID into something that is safe to use as a file name.
+fn file_stem(lot: &str) -> String {
+    lot.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+/// Work out where new parts should be written in an existing lot file: over
+/// its MRR if it has one, or else after its last complete record.
+fn find_part_offset(file: &mut File) -> io::Result<u64> {
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(&mut *file);
+    reader.seek(SeekFrom::Start(0))?;
+    let mut offset = 0;
+    while offset + 4 <= file_len {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+        let len = u16::from_le_bytes([header[0], header[1]]) as u64;
+        if (header[2], header[3]) == MRR || offset + 4 + len > file_len {
+            break;
+        }
+        reader.seek(SeekFrom::Current(len as i64))?;
+        offset += 4 + len;
+    }
+    Ok(offset)
+}
+
+/// Writes each recorded run out as an STDF V4 file.
+pub struct StdfWriter {
+    dir: PathBuf,
+
+    /// If set, every run is added to the one file for this lot, rather than
+    /// each getting its own.
+    lot: Option<String>,
+}
+
+impl StdfWriter {
+    pub fn new(dir: &Path, lot: Option<String>) -> io::Result<StdfWriter> {
+        fs::create_dir_all(dir)?;
+        Ok(StdfWriter {
+            dir: dir.to_path_buf(),
+            lot,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Write out `run`, returning the file it went to.
+    pub fn write_run(&self, run: &RunSummary) -> io::Result<PathBuf> {
+        match self.lot {
+            None => self.write_single(run),
+            Some(ref lot) => self.append_to_lot(run, lot),
+        }
+    }
+
+    fn write_single(&self, run: &RunSummary) -> io::Result<PathBuf> {
+        let path = self.dir.join(format!("run-{}.stdf", run.id));
+        let mut out = vec![];
+        far_record().write_to(&mut out)?;
+        let lot = match run.identity {
+            Some(DutIdentity { lot: Some(ref lot), .. }) => lot.clone(),
+            _ => run.id.to_string(),
+        };
+        mir_record(run, &lot).write_to(&mut out)?;
+        for r in part_records(run) {
+            r.write_to(&mut out)?;
+        }
+        mrr_record().write_to(&mut out)?;
+        File::create(&path)?.write_all(&out)?;
+        Ok(path)
+    }
+
+    /// Add a part to the lot's file, moving the MRR to the end.
+    fn append_to_lot(&self, run: &RunSummary, lot: &str) -> io::Result<PathBuf> {
+        let path = self.dir.join(format!("{}.stdf", file_stem(lot)));
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+
+        let mut out = vec![];
+        let start = find_part_offset(&mut file)?;
+        if start == 0 {
+            far_record().write_to(&mut out)?;
+            mir_record(run, lot).write_to(&mut out)?;
+        }
+        for r in part_records(run) {
+            r.write_to(&mut out)?;
+        }
+        mrr_record().write_to(&mut out)?;
+
+        file.set_len(start)?;
+        file.seek(SeekFrom::Start(start))?;
+        file.write_all(&out)?;
+        file.flush()?;
+        Ok(path)
+    }
+}
@@ -0,0 +1,210 @@
+use std::io::{self, BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use events::{EventBus, StateEvent};
+use logstore::LogStore;
+use super::{shut_down, unix_time, LogMessage, Monitor};
+
+/// How long exclave has to exit by itself when we shut down, before it is killed.
+const STOP_TIMEOUT_SECS: u64 = 5;
+
+/// The exclave process we started, if any.  It lives here rather than with
+/// the connection so that shutting down can reach it from any thread.
+static SUPERVISED: Mutex<Supervised> = Mutex::new(Supervised { child: None, stopping: false });
+
+struct Supervised {
+    child: Option<Child>,
+
+    /// Set once we are shutting down, after which no new process is started.
+    stopping: bool,
+}
+
+/// How exclave is started when the interface supervises it.
+pub struct ExclaveCommand {
+    pub program: String,
+    args: Vec<String>,
+
+    /// The tests directory, which exclave is run from.
+    dir: Option<PathBuf>,
+}
+
+impl ExclaveCommand {
+    /// `command_line` is split on whitespace.  There is no quoting.
+    pub fn new(command_line: &str, dir: Option<&str>) -> Result<ExclaveCommand, String> {
+        let mut words = command_line.split_whitespace().map(|w| w.to_string());
+        let program = match words.next() {
+            Some(p) => p,
+            None => return Err("The exclave command line is empty".to_string()),
+        };
+        Ok(ExclaveCommand {
+            program,
+            args: words.collect(),
+            dir: dir.map(PathBuf::from),
+        })
+    }
+}
+
+/// What /current.json shows about the exclave process we started.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChildStatus {
+    /// Whether it is running right now.
+    pub running: bool,
+
+    /// Process ID of the current or most recent process.
+    pub pid: Option<u32>,
+
+    /// When the current or most recent process was started.
+    pub started: Option<Duration>,
+
+    /// Number of times it has been started again after stopping.
+    pub restarts: u32,
+
+    /// How the most recent process ended, such as "exit status: 1".
+    pub last_exit: Option<String>,
+}
+
+/// The ends of a started exclave's stdin and stdout that we talk over.
+pub struct Pipes {
+    pub pid: u32,
+    pub stdout: ChildStdout,
+    pub stdin: ChildStdin,
+}
+
+/// Start exclave, with its stdin and stdout connected to us.
+pub fn spawn(command: &ExclaveCommand, monitor: &Monitor) -> io::Result<Pipes> {
+    let mut supervised = SUPERVISED.lock().unwrap();
+    if supervised.stopping {
+        return Err(io::Error::other("Shutting down"));
+    }
+
+    let mut cmd = Command::new(&command.program);
+    cmd.args(&command.args)
+       .stdin(Stdio::piped())
+       .stdout(Stdio::piped())
+       .stderr(Stdio::piped());
+    if let Some(ref dir) = command.dir {
+        cmd.current_dir(dir);
+    }
+    let mut child = cmd.spawn()?;
+
+    {
+        let mut data = monitor.state.lock().unwrap();
+        let status = data.exclave.get_or_insert_with(ChildStatus::default);
+        if status.started.is_some() {
+            status.restarts += 1;
+        }
+        status.running = true;
+        status.pid = Some(child.id());
+        status.started = Some(unix_time());
+    }
+
+    let stderr = child.stderr.take().unwrap();
+    let logs = monitor.logs.clone();
+    let events = monitor.events.clone();
+    thread::spawn(move || log_stderr(stderr, &logs, &events));
+
+    let pipes = Pipes {
+        pid: child.id(),
+        stdout: child.stdout.take().unwrap(),
+        stdin: child.stdin.take().unwrap(),
+    };
+    supervised.child = Some(child);
+    Ok(pipes)
+}
+
+/// Put each line exclave writes to stderr into the log.
+fn log_stderr(stderr: ChildStderr, logs: &Arc<Mutex<LogStore>>, events: &Arc<Mutex<EventBus>>) {
+    for line in BufReader::new(stderr).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => return,
+        };
+        let log = LogMessage {
+            seq: 0,
+            message_class: "stderr".to_string(),
+            unit_id: "exclave".to_string(),
+            unit_type: "supervisor".to_string(),
+            timestamp: unix_time(),
+            message: line,
+        };
+        let log = logs.lock().unwrap().push(log);
+        events.lock().unwrap().publish(StateEvent::Log { log });
+    }
+}
+
+/// Make sure exclave has stopped, and record how it ended.  Does nothing
+/// if stop() has already taken care of it.
+pub fn reap(monitor: &Monitor) {
+    let mut child = match SUPERVISED.lock().unwrap().child.take() {
+        Some(c) => c,
+        None => return,
+    };
+
+    // Its output has closed, so it is probably exiting already, but it may
+    // have closed stdout and carried on.
+    if let Ok(None) = child.try_wait() {
+        thread::sleep(Duration::from_millis(500));
+        if let Ok(None) = child.try_wait() {
+            let _ = child.kill();
+        }
+    }
+    let exit = match child.wait() {
+        Ok(status) => status.to_string(),
+        Err(e) => format!("unknown ({})", e),
+    };
+    eprintln!("Exclave stopped with {}", exit);
+
+    let mut data = monitor.state.lock().unwrap();
+    if let Some(ref mut status) = data.exclave {
+        status.running = false;
+        status.last_exit = Some(exit);
+    }
+}
+
+/// Wait for the exclave we started, if any, to exit, killing it if it takes
+/// longer than STOP_TIMEOUT_SECS, so that it doesn't outlive us.  None is
+/// started again after this.
+pub fn stop() {
+    let child = {
+        let mut supervised = SUPERVISED.lock().unwrap();
+        supervised.stopping = true;
+        supervised.child.take()
+    };
+    let mut child = match child {
+        Some(c) => c,
+        None => return,
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(STOP_TIMEOUT_SECS);
+    while let Ok(None) = child.try_wait() {
+        if Instant::now() >= deadline {
+            eprintln!("Exclave didn't stop within {} seconds, so killing it", STOP_TIMEOUT_SECS);
+            let _ = child.kill();
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    match child.wait() {
+        Ok(status) => eprintln!("Exclave stopped with {}", status),
+        Err(e) => eprintln!("Unable to wait for exclave to stop: {}", e),
+    }
+}
+
+/// Shut down on SIGINT or SIGTERM the same way as for /api/v1/exit, so
+/// that exclave is told to stop rather than being left running.
+pub fn shut_down_on_signal() -> io::Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            shut_down(format!("Received signal {}", signal));
+        }
+    });
+    Ok(())
+}
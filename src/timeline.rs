@@ -0,0 +1,49 @@
+use iron::prelude::*;
+use iron::status;
+use iron::mime::Mime;
+use urlencoded;
+
+use std::sync::{Arc, Mutex};
+
+use serde_json;
+
+use history::{RunHistory, RunSummary};
+use super::InterfaceState;
+
+/// Serve /timeline.json: the tests of a run in the order they started, with
+/// how long each one took.  Shows the current run unless "run" is given.
+pub fn show_timeline_json(request: &mut Request,
+                          state: &Arc<Mutex<InterfaceState>>,
+                          history: &Arc<Mutex<RunHistory>>) -> IronResult<Response> {
+    let content_type = "application/json".parse::<Mime>().unwrap();
+    let run_id = match request.get_ref::<urlencoded::UrlEncodedQuery>() {
+        Ok(query) => query.get("run").map(|s| s[0].clone()),
+        Err(_) => None,
+    };
+
+    let summary = match run_id {
+        None => {
+            let data = state.lock().unwrap();
+            match data.run {
+                Some(ref run) => RunSummary::from_state(&data, run),
+                None => return Ok(Response::with((status::NotFound, "No run has been started"))),
+            }
+        },
+        Some(id) => {
+            let record = match id.parse() {
+                Ok(id) => history.lock().unwrap().get(id),
+                Err(_) => return Ok(Response::with((status::BadRequest, format!("Unable to parse run value: {:?}", id)))),
+            };
+            match record {
+                Some(record) => record.run,
+                None => return Ok(Response::with((status::NotFound, format!("No such run: {}", id)))),
+            }
+        },
+    };
+
+    // Tests that never started sort to the end, in scenario order.
+    let mut summary = summary;
+    summary.tests.sort_by_key(|test| (test.timing.started.is_none(), test.timing.started));
+
+    Ok(Response::with((content_type, status::Ok, serde_json::to_string(&summary).unwrap())))
+}
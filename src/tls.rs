@@ -0,0 +1,201 @@
+use hyper::Error as HttpError;
+use hyper::net::{HttpStream, NetworkStream, SslServer};
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::WebPkiClientVerifier;
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest a client may take over the whole TLS handshake.
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+/// The PEM files that the server's TLS settings come from.
+struct TlsFiles {
+    cert: PathBuf,
+    key: PathBuf,
+
+    /// CA bundle that every client's certificate must be signed by.  With
+    /// it, clients without a certificate are refused, browsers included.
+    /// Without it, clients don't need certificates.
+    client_ca: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    fn load(&self) -> Result<ServerConfig, String> {
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+                        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                        .map_err(|e| format!("Unable to read certificates from {}: {}", self.cert.display(), e))?;
+        if certs.is_empty() {
+            return Err(format!("No certificates found in {}", self.cert.display()));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+                      .map_err(|e| format!("Unable to read a private key from {}: {}", self.key.display(), e))?;
+
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+                          .with_safe_default_protocol_versions()
+                          .map_err(|e| e.to_string())?;
+        let builder = match self.client_ca {
+            None => builder.with_no_client_auth(),
+            Some(ref path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))? {
+                    let cert = cert.map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+                    roots.add(cert).map_err(|e| format!("Invalid CA certificate in {}: {}", path.display(), e))?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                                   .build()
+                                   .map_err(|e| format!("Unable to use {} to check clients: {}", path.display(), e))?;
+                builder.with_client_cert_verifier(verifier)
+            },
+        };
+        builder.with_single_cert(certs, key).map_err(|e| format!("Unable to use {} and {}: {}", self.cert.display(), self.key.display(), e))
+    }
+}
+
+/// Serves HTTPS, with settings that can be reloaded while running.
+#[derive(Clone)]
+pub struct TlsServer {
+    files: Arc<TlsFiles>,
+
+    /// Used for every new connection.  Connections already open carry on
+    /// with whatever they started with.
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsServer {
+    pub fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<TlsServer, String> {
+        let files = TlsFiles {
+            cert: PathBuf::from(cert),
+            key: PathBuf::from(key),
+            client_ca: client_ca.map(PathBuf::from),
+        };
+        let config = files.load()?;
+        Ok(TlsServer {
+            files: Arc::new(files),
+            config: Arc::new(RwLock::new(Arc::new(config))),
+        })
+    }
+
+    /// Read the certificate, key, and CA bundle again.  If any of them are
+    /// no good, the current settings are kept.
+    pub fn reload(&self) -> Result<(), String> {
+        let config = self.files.load()?;
+        *self.config.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+}
+
+/// Reload `server`'s certificates whenever the process gets SIGHUP.
+pub fn reload_on_sighup(server: TlsServer) -> io::Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            match server.reload() {
+                Ok(()) => eprintln!("Reloaded TLS certificates"),
+                Err(e) => eprintln!("Keeping the old TLS certificates: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn tls_error<E: Into<Box<dyn Error + Send + Sync>>>(e: E) -> HttpError {
+    HttpError::Ssl(e.into())
+}
+
+impl SslServer for TlsServer {
+    type Stream = TlsStream;
+
+    fn wrap_server(&self, mut stream: HttpStream) -> Result<TlsStream, HttpError> {
+        let config = self.config.read().unwrap().clone();
+        let mut connection = ServerConnection::new(config).map_err(tls_error)?;
+
+        // Finish the handshake here, so that clients without an acceptable
+        // certificate are turned away before any request is read.  Each read
+        // and write may only take what's left of the deadline, so a client
+        // can't hold the connection open by sending a byte at a time.
+        let deadline = Instant::now() + Duration::from_secs(HANDSHAKE_TIMEOUT_SECS);
+        while connection.is_handshaking() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(tls_error("The TLS handshake took too long"));
+            }
+            stream.0.set_read_timeout(Some(left))?;
+            stream.0.set_write_timeout(Some(left))?;
+
+            while connection.wants_write() {
+                connection.write_tls(&mut stream)?;
+            }
+            if connection.is_handshaking() && connection.wants_read() {
+                if connection.read_tls(&mut stream)? == 0 {
+                    return Err(tls_error("The client closed the connection during the TLS handshake"));
+                }
+                if let Err(e) = connection.process_new_packets() {
+                    // Tell the client why, if it's still listening.
+                    let _ = connection.write_tls(&mut stream);
+                    return Err(tls_error(e));
+                }
+            }
+        }
+        while connection.wants_write() {
+            connection.write_tls(&mut stream)?;
+        }
+        stream.0.set_read_timeout(None)?;
+        stream.0.set_write_timeout(None)?;
+
+        Ok(TlsStream(Arc::new(Mutex::new(StreamOwned::new(connection, stream)))))
+    }
+}
+
+/// An HTTPS connection.  Hyper needs to clone streams, so the TLS state is shared.
+#[derive(Clone)]
+pub struct TlsStream(Arc<Mutex<StreamOwned<ServerConnection, HttpStream>>>);
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.lock().unwrap().sock.peer_addr()
+    }
+
+    fn set_read_timeout(&self, duration: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().sock.set_read_timeout(duration)
+    }
+
+    fn set_write_timeout(&self, duration: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().sock.set_write_timeout(duration)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        let mut stream = self.0.lock().unwrap();
+        stream.conn.send_close_notify();
+        // The client may already have gone, in which case there's nobody to tell.
+        let _ = stream.flush();
+        stream.sock.close(how)
+    }
+}
@@ -0,0 +1,168 @@
+use iron::prelude::*;
+use iron::status;
+use iron::mime::Mime;
+use urlencoded;
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde_json;
+
+use audit;
+use super::unix_time;
+
+/// Default number of entries to keep before the oldest are dropped.
+pub const DEFAULT_CAPACITY: usize = 10000;
+
+/// Shared by cfti_send and stdin_monitor, which have no state in common.
+static TRACE: Mutex<ProtocolTrace> = Mutex::new(ProtocolTrace::new());
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Received from the server.
+    Incoming,
+
+    /// Sent to the server.
+    Outgoing,
+}
+
+impl Direction {
+    fn arrow(&self) -> &'static str {
+        match *self {
+            Direction::Incoming => "<-",
+            Direction::Outgoing => "->",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct TraceEntry {
+    direction: Direction,
+    timestamp: Duration,
+
+    /// The line as it was sent or received, without its newline.
+    raw: String,
+
+    /// What the line was understood to mean.
+    decoded: String,
+}
+
+/// The most recent lines sent to and received from the server.
+struct ProtocolTrace {
+    enabled: bool,
+    capacity: usize,
+    entries: VecDeque<TraceEntry>,
+
+    /// Entries thrown away to stay within `capacity`.
+    dropped: u64,
+}
+
+impl ProtocolTrace {
+    const fn new() -> ProtocolTrace {
+        ProtocolTrace {
+            enabled: false,
+            capacity: DEFAULT_CAPACITY,
+            entries: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+}
+
+/// Set whether tracing starts out on, and how many entries to keep.
+pub fn configure(enabled: bool, capacity: usize) {
+    let mut trace = TRACE.lock().unwrap();
+    trace.enabled = enabled;
+    trace.capacity = capacity;
+}
+
+/// Add a line to the trace, if tracing is on.  `decoded` is only called
+/// if it is, so that it costs nothing otherwise.
+pub fn record<F: FnOnce() -> String>(direction: Direction, raw: &str, decoded: F) {
+    let mut trace = TRACE.lock().unwrap();
+    if !trace.enabled {
+        return;
+    }
+
+    trace.entries.push_back(TraceEntry {
+        direction,
+        timestamp: unix_time(),
+        raw: raw.trim_end_matches(['\r', '\n']).to_string(),
+        decoded: decoded(),
+    });
+    while trace.entries.len() > trace.capacity {
+        trace.entries.pop_front();
+        trace.dropped += 1;
+    }
+}
+
+/// Serve /protocol/trace, as plain text or, with "?format=ndjson", as one
+/// JSON object per line.
+pub fn show_trace(request: &mut Request) -> IronResult<Response> {
+    let format = match request.get_ref::<urlencoded::UrlEncodedQuery>() {
+        Ok(query) => query.get("format").map(|f| f[0].clone()),
+        Err(_) => None,
+    };
+
+    let trace = TRACE.lock().unwrap();
+    let mut out = String::new();
+    match format.as_deref() {
+        None | Some("text") => {
+            if trace.dropped > 0 {
+                writeln!(out, "# {} older entries were dropped", trace.dropped).unwrap();
+            }
+            for entry in &trace.entries {
+                writeln!(out, "{}.{:09} {} {}", entry.timestamp.as_secs(), entry.timestamp.subsec_nanos(), entry.direction.arrow(), entry.raw).unwrap();
+                writeln!(out, "    {}", entry.decoded).unwrap();
+            }
+            let content_type = "text/plain".parse::<Mime>().unwrap();
+            Ok(Response::with((content_type, status::Ok, out)))
+        },
+        Some("ndjson") => {
+            for entry in &trace.entries {
+                writeln!(out, "{}", serde_json::to_string(entry).unwrap()).unwrap();
+            }
+            let content_type = "application/x-ndjson".parse::<Mime>().unwrap();
+            Ok(Response::with((content_type, status::Ok, out)))
+        },
+        Some(f) => Ok(Response::with((status::BadRequest, format!("Unknown trace format {:?}.  Use text or ndjson.", f)))),
+    }
+}
+
+/// Turn tracing on or off.  What was already traced is kept.
+pub fn set_enabled(enabled: bool) {
+    TRACE.lock().unwrap().enabled = enabled;
+}
+
+/// Throw away everything traced so far.
+pub fn clear() {
+    let mut trace = TRACE.lock().unwrap();
+    trace.entries.clear();
+    trace.dropped = 0;
+}
+
+/// Serve /protocol/trace/enable, with --legacy-get.
+pub fn enable_trace(request: &mut Request) -> IronResult<Response> {
+    set_enabled(true);
+    let response = Ok(Response::with((status::Ok, "Protocol tracing enabled")));
+    audit::record_response(request, "trace", &[("enabled", "true".to_string())], &response);
+    response
+}
+
+/// Serve /protocol/trace/disable, with --legacy-get.
+pub fn disable_trace(request: &mut Request) -> IronResult<Response> {
+    set_enabled(false);
+    let response = Ok(Response::with((status::Ok, "Protocol tracing disabled")));
+    audit::record_response(request, "trace", &[("enabled", "false".to_string())], &response);
+    response
+}
+
+/// Serve /protocol/trace/clear, with --legacy-get.
+pub fn clear_trace(request: &mut Request) -> IronResult<Response> {
+    clear();
+    let response = Ok(Response::with((status::Ok, "Protocol trace cleared")));
+    audit::record_response(request, "clear_trace", &[], &response);
+    response
+}
@@ -0,0 +1,197 @@
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use supervisor::{self, ExclaveCommand};
+use super::Monitor;
+
+/// Longest to wait between attempts to reach the server.
+const MAX_RETRY_DELAY_SECS: u64 = 30;
+
+/// A supervised exclave that stops sooner than this after starting is
+/// restarted after a growing delay, in case it's failing straight away.
+const HEALTHY_RUN_SECS: u64 = 60;
+
+/// Where cfti_send writes to.
+static OUTPUT: Mutex<Output> = Mutex::new(Output::Stdout);
+
+enum Output {
+    Stdout,
+    Connected(Box<dyn Write + Send>),
+
+    /// Another transport is in use, but the server isn't connected to it.
+    NotConnected,
+}
+
+/// Send a line to the server, over whichever transport is in use.
+pub fn write_line(line: &str) -> io::Result<()> {
+    match *OUTPUT.lock().unwrap() {
+        Output::Stdout => {
+            let tx = io::stdout();
+            let mut tx = tx.lock();
+            writeln!(tx, "{}", line)
+        },
+        Output::Connected(ref mut server) => writeln!(server, "{}", line),
+        Output::NotConnected => Err(io::Error::new(io::ErrorKind::NotConnected, "The server is not connected")),
+    }
+}
+
+/// How the server's messages reach us.
+pub enum Transport {
+    /// Over stdin and stdout, as an exclave interface unit.
+    Stdio,
+    TcpListen(TcpListener),
+    TcpConnect(String),
+    UnixListen(UnixListener, PathBuf),
+    UnixConnect(PathBuf),
+
+    /// Start exclave ourselves and talk over its stdin and stdout.
+    Child(ExclaveCommand),
+}
+
+/// One connection to the server.
+struct Connection {
+    reader: Box<dyn Read + Send>,
+    writer: Box<dyn Write + Send>,
+
+    /// What to call it in messages.
+    name: String,
+
+    /// Whether the other end is an exclave process that we started.
+    supervised: bool,
+}
+
+impl Connection {
+    fn new<S: Read + Write + Send + 'static>(stream: S, name: String, try_clone: fn(&S) -> io::Result<S>) -> io::Result<Connection> {
+        let writer = try_clone(&stream)?;
+        Ok(Connection {
+            reader: Box::new(stream),
+            writer: Box::new(writer),
+            name,
+            supervised: false,
+        })
+    }
+
+    fn from_child(pipes: supervisor::Pipes) -> Connection {
+        Connection {
+            reader: Box::new(pipes.stdout),
+            writer: Box::new(pipes.stdin),
+            name: format!("exclave (process {})", pipes.pid),
+            supervised: true,
+        }
+    }
+}
+
+/// Remove a Unix socket left behind by a previous run, so it can be bound again.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref m) if m.file_type().is_socket() => fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists, "File exists and is not a socket")),
+        Err(_) => Ok(()),
+    }
+}
+
+impl Transport {
+    /// Set up a transport from its description: "stdio", "tcp-listen:ADDRESS:PORT",
+    /// "tcp-connect:HOST:PORT", "unix-listen:PATH", or "unix-connect:PATH".
+    /// Listening transports start listening right away.
+    pub fn open(spec: &str) -> Result<Transport, String> {
+        let (kind, address) = match spec.split_once(':') {
+            Some(parts) => parts,
+            None if spec == "stdio" => return Ok(Transport::Stdio),
+            None => return Err(format!("Unknown transport {:?}", spec)),
+        };
+        *OUTPUT.lock().unwrap() = Output::NotConnected;
+        match kind {
+            "tcp-listen" => TcpListener::bind(address)
+                                        .map(Transport::TcpListen)
+                                        .map_err(|e| format!("Unable to listen on {}: {}", address, e)),
+            "tcp-connect" => Ok(Transport::TcpConnect(address.to_string())),
+            "unix-listen" => remove_stale_socket(Path::new(address))
+                                        .and_then(|_| UnixListener::bind(address))
+                                        .map(|l| Transport::UnixListen(l, PathBuf::from(address)))
+                                        .map_err(|e| format!("Unable to listen on {}: {}", address, e)),
+            "unix-connect" => Ok(Transport::UnixConnect(PathBuf::from(address))),
+            _ => Err(format!("Unknown transport {:?}", kind)),
+        }
+    }
+
+    /// Start exclave as a child process, restarting it whenever it stops.
+    pub fn supervise(command: ExclaveCommand) -> Transport {
+        *OUTPUT.lock().unwrap() = Output::NotConnected;
+        Transport::Child(command)
+    }
+
+    /// Whether the server is there from the start, rather than connecting later.
+    pub fn is_stdio(&self) -> bool {
+        matches!(self, Transport::Stdio)
+    }
+
+    /// Wait for the server to connect, or keep trying to connect to it.
+    fn next_connection(&self, monitor: &Monitor) -> Connection {
+        let mut delay = Duration::from_secs(1);
+        loop {
+            let result = match *self {
+                Transport::Stdio => unreachable!(),
+                Transport::TcpListen(ref listener) => listener.accept().and_then(|(stream, peer)| {
+                    Connection::new(stream, format!("the TCP connection from {}", peer), TcpStream::try_clone)
+                }),
+                Transport::TcpConnect(ref address) => TcpStream::connect(address).and_then(|stream| {
+                    Connection::new(stream, format!("the TCP connection to {}", address), TcpStream::try_clone)
+                }),
+                Transport::UnixListen(ref listener, ref path) => listener.accept().and_then(|(stream, _)| {
+                    Connection::new(stream, format!("the Unix socket {}", path.display()), UnixStream::try_clone)
+                }),
+                Transport::UnixConnect(ref path) => UnixStream::connect(path).and_then(|stream| {
+                    Connection::new(stream, format!("the Unix socket {}", path.display()), UnixStream::try_clone)
+                }),
+                Transport::Child(ref command) => supervisor::spawn(command, monitor)
+                                                             .map(Connection::from_child)
+                                                             .map_err(|e| io::Error::new(e.kind(), format!("Unable to start {}: {}", command.program, e))),
+            };
+            match result {
+                Ok(connection) => return connection,
+                Err(e) => {
+                    eprintln!("Unable to reach the server: {}.  Trying again in {:?}.", e, delay);
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(Duration::from_secs(MAX_RETRY_DELAY_SECS));
+                },
+            }
+        }
+    }
+}
+
+/// Serve one server connection after another over `transport`, forever.
+pub fn run(transport: Transport, mut monitor: Monitor) {
+    let mut restart_delay = Duration::from_secs(1);
+    loop {
+        let connection = transport.next_connection(&monitor);
+        let connected_at = Instant::now();
+        eprintln!("Connected to the server over {}", connection.name);
+        *OUTPUT.lock().unwrap() = Output::Connected(connection.writer);
+        monitor.connected();
+
+        let reason = monitor.read(BufReader::new(connection.reader), &connection.name);
+        *OUTPUT.lock().unwrap() = Output::NotConnected;
+        if connection.supervised {
+            supervisor::reap(&monitor);
+        }
+        monitor.disconnected(reason);
+
+        // Don't restart a crashing exclave over and over as fast as we can.
+        if let Transport::Child(_) = transport {
+            if connected_at.elapsed() >= Duration::from_secs(HEALTHY_RUN_SECS) {
+                restart_delay = Duration::from_secs(1);
+            }
+            eprintln!("Restarting exclave in {:?}", restart_delay);
+            thread::sleep(restart_delay);
+            restart_delay = (restart_delay * 2).min(Duration::from_secs(MAX_RETRY_DELAY_SECS));
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use websocket::OwnedMessage;
+use websocket::url::form_urlencoded;
+use websocket::sync::{Server, Writer};
+use websocket::server::NoTlsAcceptor;
+use websocket::server::upgrade::sync::Upgrade;
+
+use std::io;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{self, Value};
+
+use audit::{self, Source};
+use auth::{Auth, Caller, Role};
+use events::{EventBus, StateEvent, Subscription};
+use super::{cfti_send, InterfaceState, OutgoingMessage, SERVER_SIGNATURE};
+
+/// Path that WebSocket clients must connect to.
+const WS_PATH: &str = "/ws";
+
+/// How long the connection may sit idle before it is pinged, so that dead
+/// clients get noticed.
+const PING_INTERVAL_SECS: u64 = 15;
+
+/// A control request from a WebSocket client.  Each one maps onto an OutgoingMessage.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    /// Start a scenario, or the current one if none is given.
+    Start { scenario: Option<String> },
+
+    /// Abort the running scenario.
+    Abort {},
+
+    /// Select a new scenario.
+    Scenario { scenario: String },
+
+    /// Send HELLO to identify this server.
+    Hello {},
+
+    /// Request the current jig.
+    Jig {},
+
+    /// Request the list of scenarios.
+    Scenarios {},
+
+    /// Request the list of tests.
+    Tests {},
+}
+
+/// Messages sent to WebSocket clients.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Reply<'a> {
+    /// Response to a Command, carrying the "id" the client sent with it.
+    Ack { id: Value, ok: bool, message: String },
+
+    /// A state change, identical to the ones sent on /events.
+    Event { event_id: u64, event: &'a StateEvent },
+
+    /// The events requested with "last_event_id" are no longer available,
+    /// and the client must reload /current.json.
+    Reset { event_id: u64 },
+}
+
+fn run_command(command: Command, state: &Arc<Mutex<InterfaceState>>, source: &Source) -> String {
+    match command {
+        Command::Start { scenario } => {
+            let scenario_id = match scenario {
+                Some(s) => s,
+                None => state.lock().unwrap().scenario.clone(),
+            };
+            cfti_send(OutgoingMessage::StartTests(scenario_id.clone()));
+            audit::record(source, "start", &[("scenario", scenario_id.clone())], None);
+            format!("Starting {} scenario", scenario_id)
+        },
+        Command::Abort {} => {
+            cfti_send(OutgoingMessage::AbortTests);
+            audit::record(source, "abort", &[], None);
+            "Aborting tests".to_string()
+        },
+        Command::Scenario { scenario } => {
+            cfti_send(OutgoingMessage::Scenario(scenario.clone()));
+            audit::record(source, "scenario", &[("scenario", scenario.clone())], None);
+            format!("Selecting scenario {}", scenario)
+        },
+        Command::Hello {} => {
+            cfti_send(OutgoingMessage::Hello(SERVER_SIGNATURE.to_string()));
+            audit::record(source, "hello", &[], None);
+            "Sending HELLO".to_string()
+        },
+        Command::Jig {} => {
+            cfti_send(OutgoingMessage::GetJig);
+            "Requesting jig id".to_string()
+        },
+        Command::Scenarios {} => {
+            cfti_send(OutgoingMessage::Scenarios);
+            "Sending SCENARIOS".to_string()
+        },
+        Command::Tests {} => {
+            cfti_send(OutgoingMessage::GetTests);
+            "Requesting test list".to_string()
+        },
+    }
+}
+
+/// Parse a command from a client and run it, returning the acknowledgement.
+/// Every command needs at least the operator role.
+fn handle_command(text: &str, state: &Arc<Mutex<InterfaceState>>, role: Role, source: &Source) -> Reply<'static> {
+    let value: Value = match serde_json::from_str(text) {
+        Ok(v) => v,
+        Err(e) => return Reply::Ack { id: Value::Null, ok: false, message: format!("Unable to parse command: {}", e) },
+    };
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    if role < Role::Operator {
+        return Reply::Ack { id, ok: false, message: format!("Commands need the {} role or above", Role::Operator) };
+    }
+
+    match serde_json::from_value(value) {
+        Ok(command) => Reply::Ack { id, ok: true, message: run_command(command, state, source) },
+        Err(e) => Reply::Ack { id, ok: false, message: format!("Unrecognized command: {}", e) },
+    }
+}
+
+fn send_reply(writer: &Mutex<Writer<TcpStream>>, reply: &Reply) -> bool {
+    let text = serde_json::to_string(reply).unwrap();
+    writer.lock().unwrap().send_message(&OwnedMessage::Text(text)).is_ok()
+}
+
+/// Pass state changes on to the client until it goes away.
+fn forward_events(subscription: Subscription, last_id: u64, writer: Arc<Mutex<Writer<TcpStream>>>) {
+    match subscription.missed {
+        Some(missed) => for (event_id, event) in missed {
+            if !send_reply(&writer, &Reply::Event { event_id, event: &event }) {
+                return;
+            }
+        },
+        None => if !send_reply(&writer, &Reply::Reset { event_id: last_id }) {
+            return;
+        },
+    }
+
+    loop {
+        let sent = match subscription.receiver.recv_timeout(Duration::from_secs(PING_INTERVAL_SECS)) {
+            Ok((event_id, event)) => send_reply(&writer, &Reply::Event { event_id, event: &event }),
+            Err(RecvTimeoutError::Timeout) => writer.lock().unwrap().send_message(&OwnedMessage::Ping(vec![])).is_ok(),
+            Err(RecvTimeoutError::Disconnected) => {
+                // Dropped for falling behind, so close the connection.  The
+                // client will be told to reset when it reconnects.
+                let _ = writer.lock().unwrap().send_message(&OwnedMessage::Close(None));
+                return;
+            },
+        };
+        if !sent {
+            return;
+        }
+    }
+}
+
+/// Pull `key` out of a request URI's query, such as "last_event_id" from
+/// "/ws?last_event_id=12", undoing any percent-encoding.
+fn query_value(query: &str, key: &str) -> Option<String> {
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.into_owned())
+}
+
+fn handle_connection(connection: Upgrade<TcpStream>, state: Arc<Mutex<InterfaceState>>, events: Arc<Mutex<EventBus>>, auth: Option<Arc<Auth>>) {
+    let uri = connection.uri();
+    let mut parts = uri.splitn(2, '?');
+    let path = parts.next().unwrap_or("");
+    let query = parts.next().unwrap_or("");
+    let last_event_id = query_value(query, "last_event_id").and_then(|v| v.parse().ok());
+
+    if path != WS_PATH {
+        let _ = connection.reject();
+        return;
+    }
+
+    // Browsers can't set headers on a WebSocket, so a token may be given
+    // as "access_token" instead.
+    let caller: Option<Caller> = match auth {
+        None => None,
+        Some(ref auth) => match auth.check(&connection.request.headers, query_value(query, "access_token").as_deref(), Role::Viewer) {
+            Ok(caller) => Some(caller),
+            Err(refusal) => {
+                eprintln!("Refusing WebSocket connection: {}", refusal);
+                let _ = connection.reject();
+                return;
+            },
+        },
+    };
+    let role = caller.as_ref().map_or(Role::Admin, |c| c.role);
+    let source = Source::new(connection.stream.peer_addr().ok(), &connection.request.headers, caller.as_ref());
+
+    let client = match connection.accept() {
+        Ok(c) => c,
+        Err((_, e)) => { eprintln!("Unable to accept WebSocket connection: {}", e); return; },
+    };
+    let (mut reader, writer) = match client.split() {
+        Ok(rw) => rw,
+        Err(e) => { eprintln!("Unable to split WebSocket connection: {}", e); return; },
+    };
+    let writer = Arc::new(Mutex::new(writer));
+
+    let (subscription, last_id) = {
+        let mut events = events.lock().unwrap();
+        (events.subscribe(last_event_id), events.last_id())
+    };
+    let event_writer = writer.clone();
+    thread::spawn(move || forward_events(subscription, last_id, event_writer));
+
+    for message in reader.incoming_messages() {
+        match message {
+            Ok(OwnedMessage::Text(text)) => {
+                if !send_reply(&writer, &handle_command(&text, &state, role, &source)) {
+                    break;
+                }
+            },
+            Ok(OwnedMessage::Ping(data)) => {
+                if writer.lock().unwrap().send_message(&OwnedMessage::Pong(data)).is_err() {
+                    break;
+                }
+            },
+            Ok(OwnedMessage::Close(_)) => {
+                let _ = writer.lock().unwrap().send_message(&OwnedMessage::Close(None));
+                break;
+            },
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    }
+
+    // Closing the socket also stops the event forwarder, whose next write will fail.
+    let _ = reader.shutdown_all();
+}
+
+/// Start listening for WebSocket clients on `address`, so that a port
+/// that's in use can be reported before anything else starts.
+pub fn bind(address: &str) -> io::Result<Server<NoTlsAcceptor>> {
+    Server::bind(address)
+}
+
+/// Accept WebSocket clients forever.
+pub fn serve(server: Server<NoTlsAcceptor>, state: Arc<Mutex<InterfaceState>>, events: Arc<Mutex<EventBus>>, auth: Option<Arc<Auth>>) {
+    for connection in server.filter_map(Result::ok) {
+        let state = state.clone();
+        let events = events.clone();
+        let auth = auth.clone();
+        thread::spawn(move || handle_connection(connection, state, events, auth));
+    }
+}